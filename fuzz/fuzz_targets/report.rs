@@ -0,0 +1,20 @@
+#![no_main]
+
+use keychron_tray_rs::report::{Report, TryMerge};
+use libfuzzer_sys::fuzz_target;
+
+// Reports merged one after the other, like `KeychronHid::listen` does
+fuzz_target!(|reports: Vec<Vec<u8>>| {
+    let mut r = Report::default();
+    for report in reports {
+        if r.merge(&report).is_ok() {
+            // what the tray reads after each report
+            r.current_dpi();
+            r.current_polling_rate();
+            r.polling_rate.rates();
+            r.features();
+            r.wake_sources();
+            r.debounce.options();
+        }
+    }
+});
@@ -0,0 +1,410 @@
+use std::error;
+use std::fmt;
+use std::str;
+
+use num_enum::IntoPrimitive;
+use num_enum::TryFromPrimitive;
+
+pub const BUTTON_NUM: usize = 16;
+
+// Type codes and parameter bytes of an action, assumed like `REPORT_TYPE_BUTTON`.
+// The parameters themselves are standard: the HID button bits of a mouse, and the
+// HID keyboard and consumer usages.
+const ACTION_DISABLED: u8 = 0;
+const ACTION_MOUSE: u8 = 1;
+const ACTION_KEY: u8 = 2;
+const ACTION_MEDIA: u8 = 3;
+const ACTION_DPI: u8 = 4;
+const ACTION_MACRO: u8 = 5;
+
+const MOD_CTRL: u8 = 1;
+const MOD_SHIFT: u8 = 2;
+const MOD_ALT: u8 = 4;
+const MOD_GUI: u8 = 8;
+
+// HID keyboard usages by name
+const KEY_CODES: [(&str, u8); 30] = [
+    ("ENTER", 0x28),
+    ("ESC", 0x29),
+    ("BACKSPACE", 0x2a),
+    ("TAB", 0x2b),
+    ("SPACE", 0x2c),
+    ("MINUS", 0x2d),
+    ("EQUAL", 0x2e),
+    ("LEFTBRACKET", 0x2f),
+    ("RIGHTBRACKET", 0x30),
+    ("BACKSLASH", 0x31),
+    ("SEMICOLON", 0x33),
+    ("QUOTE", 0x34),
+    ("GRAVE", 0x35),
+    ("COMMA", 0x36),
+    ("DOT", 0x37),
+    ("SLASH", 0x38),
+    ("INSERT", 0x49),
+    ("HOME", 0x4a),
+    ("PAGEUP", 0x4b),
+    ("DELETE", 0x4c),
+    ("END", 0x4d),
+    ("PAGEDOWN", 0x4e),
+    ("RIGHT", 0x4f),
+    ("LEFT", 0x50),
+    ("DOWN", 0x51),
+    ("UP", 0x52),
+    ("CTRL", 0xe0),
+    ("SHIFT", 0xe1),
+    ("ALT", 0xe2),
+    ("SUPER", 0xe3),
+];
+
+// Buttons by the index the device uses, named after the launcher key ids
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Hash, IntoPrimitive, TryFromPrimitive)]
+#[repr(u8)]
+pub enum Button {
+    Left = 0,
+    Middle = 1,
+    Right = 2,
+    Forward = 3,
+    Backward = 4,
+    RightTilt = 8,
+    LeftTilt = 9,
+    RightScroll = 10,
+    LeftScroll = 11,
+    DownScroll = 13,
+    UpScroll = 14,
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Hash)]
+pub struct ParseButtonError;
+
+impl fmt::Display for ParseButtonError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Invalid button given")
+    }
+}
+
+impl error::Error for ParseButtonError {
+    fn description(&self) -> &str {
+        "invalid button"
+    }
+}
+
+impl fmt::Display for Button {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Button::Left => f.write_str("left"),
+            Button::Middle => f.write_str("middle"),
+            Button::Right => f.write_str("right"),
+            Button::Forward => f.write_str("forward"),
+            Button::Backward => f.write_str("backward"),
+            Button::RightTilt => f.write_str("rightTilt"),
+            Button::LeftTilt => f.write_str("leftTilt"),
+            Button::RightScroll => f.write_str("rightScroll"),
+            Button::LeftScroll => f.write_str("leftScroll"),
+            Button::DownScroll => f.write_str("downScroll"),
+            Button::UpScroll => f.write_str("upScroll"),
+        }
+    }
+}
+
+impl str::FromStr for Button {
+    type Err = ParseButtonError;
+
+    fn from_str(s: &str) -> ::std::result::Result<Self, Self::Err> {
+        match s.replace([' ', '-', '_'], "").to_uppercase().as_str() {
+            "LEFT" => Ok(Button::Left),
+            "MIDDLE" => Ok(Button::Middle),
+            "RIGHT" => Ok(Button::Right),
+            "FORWARD" => Ok(Button::Forward),
+            "BACKWARD" => Ok(Button::Backward),
+            "RIGHTTILT" => Ok(Button::RightTilt),
+            "LEFTTILT" => Ok(Button::LeftTilt),
+            "RIGHTSCROLL" => Ok(Button::RightScroll),
+            "LEFTSCROLL" => Ok(Button::LeftScroll),
+            "DOWNSCROLL" => Ok(Button::DownScroll),
+            "UPSCROLL" => Ok(Button::UpScroll),
+            _ => Err(ParseButtonError),
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Hash, IntoPrimitive, TryFromPrimitive)]
+#[repr(u8)]
+pub enum MouseButton {
+    Left = 1,
+    Right = 2,
+    Middle = 4,
+    Backward = 8,
+    Forward = 16,
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Hash, IntoPrimitive, TryFromPrimitive)]
+#[repr(u16)]
+pub enum MediaKey {
+    Next = 0xb5,
+    Previous = 0xb6,
+    Stop = 0xb7,
+    PlayPause = 0xcd,
+    Mute = 0xe2,
+    VolumeUp = 0xe9,
+    VolumeDown = 0xea,
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Hash, IntoPrimitive, TryFromPrimitive)]
+#[repr(u8)]
+pub enum DpiAction {
+    Cycle,
+    Up,
+    Down,
+}
+
+// Action a button triggers when pressed
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Hash)]
+pub enum ButtonAction {
+    Disabled,
+    Mouse(MouseButton),
+    // Modifier bits and HID keyboard usage
+    Key(u8, u8),
+    Media(MediaKey),
+    Dpi(DpiAction),
+    // Macro slot on the device
+    Macro(u8),
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Hash)]
+pub struct ParseButtonActionError;
+
+impl fmt::Display for ParseButtonActionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Invalid button action given")
+    }
+}
+
+impl error::Error for ParseButtonActionError {
+    fn description(&self) -> &str {
+        "invalid button action"
+    }
+}
+
+impl fmt::Display for ButtonAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ButtonAction::Disabled => f.write_str("disable"),
+            ButtonAction::Mouse(mb) => write!(f, "mouse:{}", format!("{:?}", mb).to_lowercase()),
+            ButtonAction::Key(modifiers, code) => {
+                f.write_str("key:")?;
+                for (bit, name) in [
+                    (MOD_CTRL, "ctrl+"),
+                    (MOD_SHIFT, "shift+"),
+                    (MOD_ALT, "alt+"),
+                    (MOD_GUI, "super+"),
+                ] {
+                    if modifiers & bit != 0 {
+                        f.write_str(name)?;
+                    }
+                }
+                match key_name(code) {
+                    Some(name) => f.write_str(name.to_lowercase().as_str()),
+                    None => write!(f, "{:#04x}", code),
+                }
+            }
+            ButtonAction::Media(mk) => write!(
+                f,
+                "media:{}",
+                match mk {
+                    MediaKey::Next => "next",
+                    MediaKey::Previous => "previous",
+                    MediaKey::Stop => "stop",
+                    MediaKey::PlayPause => "play-pause",
+                    MediaKey::Mute => "mute",
+                    MediaKey::VolumeUp => "volume-up",
+                    MediaKey::VolumeDown => "volume-down",
+                }
+            ),
+            ButtonAction::Dpi(da) => write!(f, "dpi:{}", format!("{:?}", da).to_lowercase()),
+            ButtonAction::Macro(slot) => write!(f, "macro:{}", u16::from(slot) + 1),
+        }
+    }
+}
+
+impl str::FromStr for ButtonAction {
+    type Err = ParseButtonActionError;
+
+    // `disable`, `mouse:<button>`, `key:[ctrl+][shift+][alt+][super+]<key>`, `media:<key>` or `dpi:<cycle|up|down>`
+    fn from_str(s: &str) -> ::std::result::Result<Self, Self::Err> {
+        let s = s.replace(" ", "").to_uppercase();
+        let (kind, arg) = s.split_once(':').unwrap_or((s.as_str(), ""));
+        let arg = arg.replace(['-', '_'], "");
+        match (kind, arg.as_str()) {
+            ("DISABLE" | "DISABLED" | "NONE", "") => Ok(ButtonAction::Disabled),
+            ("MOUSE", _) => mouse_button(&arg)
+                .map(ButtonAction::Mouse)
+                .ok_or(ParseButtonActionError),
+            ("MACRO", _) => match arg.parse::<u8>() {
+                Ok(n) if n > 0 => Ok(ButtonAction::Macro(n - 1)),
+                _ => Err(ParseButtonActionError),
+            },
+            ("MEDIA", "NEXT") => Ok(ButtonAction::Media(MediaKey::Next)),
+            ("MEDIA", "PREVIOUS" | "PREV") => Ok(ButtonAction::Media(MediaKey::Previous)),
+            ("MEDIA", "STOP") => Ok(ButtonAction::Media(MediaKey::Stop)),
+            ("MEDIA", "PLAYPAUSE" | "PLAY") => Ok(ButtonAction::Media(MediaKey::PlayPause)),
+            ("MEDIA", "MUTE") => Ok(ButtonAction::Media(MediaKey::Mute)),
+            ("MEDIA", "VOLUMEUP") => Ok(ButtonAction::Media(MediaKey::VolumeUp)),
+            ("MEDIA", "VOLUMEDOWN") => Ok(ButtonAction::Media(MediaKey::VolumeDown)),
+            ("DPI", "CYCLE") => Ok(ButtonAction::Dpi(DpiAction::Cycle)),
+            ("DPI", "UP") => Ok(ButtonAction::Dpi(DpiAction::Up)),
+            ("DPI", "DOWN") => Ok(ButtonAction::Dpi(DpiAction::Down)),
+            ("KEY", _) => {
+                let mut modifiers = 0u8;
+                let mut keys = arg.split('+').peekable();
+                while let Some(k) = keys.next() {
+                    if keys.peek().is_none() {
+                        let code = key_code(k).ok_or(ParseButtonActionError)?;
+                        return Ok(ButtonAction::Key(modifiers, code));
+                    }
+                    modifiers |= match k {
+                        "CTRL" | "CONTROL" => MOD_CTRL,
+                        "SHIFT" => MOD_SHIFT,
+                        "ALT" => MOD_ALT,
+                        "SUPER" | "GUI" | "META" | "WIN" => MOD_GUI,
+                        _ => return Err(ParseButtonActionError),
+                    };
+                }
+                Err(ParseButtonActionError)
+            }
+            _ => Err(ParseButtonActionError),
+        }
+    }
+}
+
+impl ButtonAction {
+    // Action type followed by its 3 parameter bytes
+    pub fn to_bytes(self) -> [u8; 4] {
+        match self {
+            ButtonAction::Disabled => [ACTION_DISABLED, 0, 0, 0],
+            ButtonAction::Mouse(mb) => [ACTION_MOUSE, mb.into(), 0, 0],
+            ButtonAction::Key(modifiers, code) => [ACTION_KEY, modifiers, code, 0],
+            ButtonAction::Media(mk) => {
+                let usage = u16::from(mk).to_le_bytes();
+                [ACTION_MEDIA, usage[0], usage[1], 0]
+            }
+            ButtonAction::Dpi(da) => [ACTION_DPI, da.into(), 0, 0],
+            ButtonAction::Macro(slot) => [ACTION_MACRO, slot, 0, 0],
+        }
+    }
+
+    pub fn from_bytes(value: &[u8; 4]) -> Option<Self> {
+        match value[0] {
+            ACTION_DISABLED => Some(ButtonAction::Disabled),
+            ACTION_MOUSE => value[1].try_into().ok().map(ButtonAction::Mouse),
+            ACTION_KEY => Some(ButtonAction::Key(value[1], value[2])),
+            ACTION_MEDIA => u16::from_le_bytes([value[1], value[2]])
+                .try_into()
+                .ok()
+                .map(ButtonAction::Media),
+            ACTION_DPI => value[1].try_into().ok().map(ButtonAction::Dpi),
+            ACTION_MACRO => Some(ButtonAction::Macro(value[1])),
+            _ => None,
+        }
+    }
+}
+
+pub fn mouse_button(name: &str) -> Option<MouseButton> {
+    match name {
+        "LEFT" => Some(MouseButton::Left),
+        "RIGHT" => Some(MouseButton::Right),
+        "MIDDLE" => Some(MouseButton::Middle),
+        "BACKWARD" | "BACK" => Some(MouseButton::Backward),
+        "FORWARD" => Some(MouseButton::Forward),
+        _ => None,
+    }
+}
+
+pub fn key_code(name: &str) -> Option<u8> {
+    let b = name.as_bytes();
+    match b {
+        [c @ b'A'..=b'Z'] => Some(c - b'A' + 0x04),
+        [b'0'] => Some(0x27),
+        [c @ b'1'..=b'9'] => Some(c - b'1' + 0x1e),
+        [b'F', ..] => match name[1..].parse::<u8>() {
+            Ok(n @ 1..=12) => Some(0x3a + n - 1),
+            _ => None,
+        },
+        // usages without a name, as displayed
+        [b'0', b'X', ..] => u8::from_str_radix(&name[2..], 16).ok(),
+        _ => KEY_CODES.iter().find(|(n, _)| *n == name).map(|(_, c)| *c),
+    }
+}
+
+pub fn key_name(code: u8) -> Option<String> {
+    match code {
+        0x04..=0x1d => Some(((code - 0x04 + b'A') as char).to_string()),
+        0x1e..=0x26 => Some(((code - 0x1e + b'1') as char).to_string()),
+        0x27 => Some("0".to_string()),
+        0x3a..=0x45 => Some(format!("F{}", code - 0x3a + 1)),
+        _ => KEY_CODES
+            .iter()
+            .find(|(_, c)| *c == code)
+            .map(|(n, _)| n.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ACTIONS: [ButtonAction; 9] = [
+        ButtonAction::Disabled,
+        ButtonAction::Mouse(MouseButton::Backward),
+        ButtonAction::Key(0, 0x04),
+        ButtonAction::Key(MOD_CTRL | MOD_SHIFT, 0x3e),
+        ButtonAction::Key(MOD_GUI, 0x64),
+        ButtonAction::Media(MediaKey::PlayPause),
+        ButtonAction::Media(MediaKey::VolumeDown),
+        ButtonAction::Dpi(DpiAction::Cycle),
+        ButtonAction::Macro(3),
+    ];
+
+    #[test]
+    fn actions_parsed() {
+        assert_eq!("key:Ctrl+Shift+F5".parse(), Ok(ACTIONS[3]));
+        assert_eq!("mouse:back".parse(), Ok(ACTIONS[1]));
+        assert_eq!("media: play".parse(), Ok(ACTIONS[5]));
+        assert_eq!("macro:4".parse(), Ok(ACTIONS[8]));
+        for s in [
+            "macro:0",
+            "key:ctrl+",
+            "key:hyper+a",
+            "mouse:side",
+            "dpi",
+            "bogus",
+        ] {
+            assert_eq!(
+                s.parse::<ButtonAction>(),
+                Err(ParseButtonActionError),
+                "{}",
+                s
+            );
+        }
+    }
+
+    #[test]
+    fn actions_displayed_as_parsed() {
+        for action in ACTIONS {
+            assert_eq!(action.to_string().parse(), Ok(action), "{}", action);
+        }
+        assert_eq!(ACTIONS[3].to_string(), "key:ctrl+shift+f5");
+        assert_eq!(ACTIONS[4].to_string(), "key:super+0x64");
+        // any slot read from a device is shown, even one beyond u8
+        assert_eq!(ButtonAction::Macro(255).to_string(), "macro:256");
+    }
+
+    #[test]
+    fn actions_encoded() {
+        for action in ACTIONS {
+            assert_eq!(ButtonAction::from_bytes(&action.to_bytes()), Some(action));
+        }
+        assert_eq!(ACTIONS[5].to_bytes(), [ACTION_MEDIA, 0xcd, 0, 0]);
+        assert_eq!(ButtonAction::from_bytes(&[ACTION_MOUSE, 3, 0, 0]), None);
+        assert_eq!(ButtonAction::from_bytes(&[ACTION_MACRO + 1, 0, 0, 0]), None);
+    }
+}
@@ -0,0 +1,581 @@
+#[cfg(any(test, feature = "simulate"))]
+use std::io::{self, Write};
+use std::{error, fmt, time::Duration};
+
+use tokio::{sync::watch, task, time};
+
+#[cfg(any(test, feature = "simulate"))]
+use crate::{
+    button::{Button, ButtonAction},
+    macros::Macro,
+};
+use crate::{
+    keychron_hid::{Command, DefaultSetting, KeychronHid},
+    report::{Feature, Lod, PollingRate, Report, WakeSource},
+    transport::DeviceInfo,
+    update,
+};
+
+const REPORT_TIMEOUT: Duration = Duration::from_secs(2);
+
+const USAGE: &str = "Usage: keychron-tray-rs [COMMAND]
+
+Without a command the tray applet is started. In builds with the simulate
+feature, setting KEYCHRON_SIMULATE runs against a simulated M6 8K instead of
+the connected devices.
+
+The commands that change a setting are not verified against a capture of the
+launcher yet, they only write to a device with KEYCHRON_ALLOW_WRITES set.
+
+Commands:
+  info                     Print the state of the device
+  dpi-stages <DPI>...      Set the DPI value of 1 to 5 stages
+  polling-rate <HZ>        Set the polling rate (125, 500, 1000, 2000, 4000, 8000)
+  profile <N>              Switch to the onboard profile N
+  debounce <MS>            Set the button debounce time
+  sleep <DURATION>         Set the idle time before sleeping (e.g. 5min, 1h)
+  lod <MM>                 Set the lift-off distance (0.7, 1.0, 2.0)
+  feature <NAME> <on|off>  Toggle a sensor feature (angle-snapping, ripple-control,
+                           motion-sync, 20k-fps)
+  scroll speed <N>         Set the scroll wheel speed (1 to 10)
+  scroll inertia <N>       Set the scroll wheel inertia (0 turns it off, up to 10)
+  scroll lines <N>         Set the lines scrolled per wheel notch (1 to 10)
+  scroll direction <DIR>   Set the scroll direction (natural, standard)
+  wake <SOURCE> <on|off>   Toggle a wake up source (key, scroll, move, side-scroll)
+  restore-default <SETTING>
+                           Restore a setting to its default (dpi-stages)
+  restore-defaults --yes   Restore the settings with a known default, so far only
+                           the DPI stages
+  check-update             Look up the latest firmware for the device, the launcher
+                           URL can be changed with KEYCHRON_LAUNCHER_URL
+  help                     Print this help";
+
+// Commands only built with the simulate feature, their reports are assumed
+#[cfg(any(test, feature = "simulate"))]
+const USAGE_SIMULATE: &str = "
+
+Commands of the simulate feature, not known to work on a device:
+  button <ID> [ACTION]     Print or set the action of a button, IDs are the launcher
+                           ones (left, right, middle, forward, backward, leftTilt,
+                           rightTilt, leftScroll, rightScroll, upScroll, downScroll)
+                           and actions are one of disable, mouse:<BUTTON>,
+                           key:[ctrl+][shift+][alt+][super+]<KEY>, media:<KEY>
+                           (play-pause, next, previous, stop, mute, volume-up,
+                           volume-down), dpi:<cycle|up|down> and macro:<SLOT>
+  macro <SLOT> <FILE>      Upload the macro in FILE to the slot (1 to 4), FILE has
+                           one event per line: down <KEY>, up <KEY>, press <KEY> or
+                           delay <MS>, where KEY may also be mouse:<BUTTON>
+  pair                     Pair a new mouse to the receiver";
+#[cfg(not(any(test, feature = "simulate")))]
+const USAGE_SIMULATE: &str = "";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Cli {
+    Tray,
+    Help,
+    Info,
+    Set(Command),
+    #[cfg(any(test, feature = "simulate"))]
+    Button(Button),
+    // Slot and path of the macro file
+    #[cfg(any(test, feature = "simulate"))]
+    Macro(u8, String),
+    CheckUpdate,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseCliError(String);
+
+impl fmt::Display for ParseCliError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}\n\n{}{}", self.0, USAGE, USAGE_SIMULATE)
+    }
+}
+
+impl error::Error for ParseCliError {
+    fn description(&self) -> &str {
+        "invalid command line"
+    }
+}
+
+impl Cli {
+    pub fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Self, ParseCliError> {
+        let Some(cmd) = args.next() else {
+            return Ok(Cli::Tray);
+        };
+        let params: Vec<String> = args.collect();
+        match cmd.as_str() {
+            "help" | "-h" | "--help" => Ok(Cli::Help),
+            "info" => Ok(Cli::Info),
+            #[cfg(any(test, feature = "simulate"))]
+            "pair" => Ok(Cli::Set(Command::Pair)),
+            "check-update" => Ok(Cli::CheckUpdate),
+            "restore-default" => match params.as_slice() {
+                [s] if s == "dpi-stages" => {
+                    Ok(Cli::Set(Command::RestoreDefault(DefaultSetting::DpiLevels)))
+                }
+                _ => Err(ParseCliError("A setting to restore expected".to_string())),
+            },
+            "restore-defaults" => match params.as_slice() {
+                [y] if y == "--yes" || y == "-y" => Ok(Cli::Set(Command::RestoreDefaults)),
+                _ => Err(ParseCliError(
+                    "Restoring the defaults overwrites the DPI stages, confirm with --yes"
+                        .to_string(),
+                )),
+            },
+            "dpi-stages" => {
+                let mut levels = [0u16; 5];
+                if params.is_empty() || params.len() > levels.len() {
+                    return Err(ParseCliError(format!(
+                        "Between 1 and {} DPI values expected",
+                        levels.len()
+                    )));
+                }
+                for (l, p) in levels.iter_mut().zip(params.iter()) {
+                    *l = p
+                        .parse()
+                        .map_err(|_| ParseCliError(format!("Invalid DPI value: {}", p)))?;
+                }
+                Ok(Cli::Set(Command::DpiLevels(levels, params.len() as u8)))
+            }
+            "polling-rate" => match params.as_slice() {
+                [p] => Ok(Cli::Set(Command::PollingRate(
+                    p.parse::<PollingRate>()
+                        .map_err(|_| ParseCliError(format!("Invalid polling rate: {}", p)))?,
+                ))),
+                _ => Err(ParseCliError("One polling rate expected".to_string())),
+            },
+            "profile" => match params.as_slice() {
+                [p] => match p.parse::<u8>() {
+                    Ok(n) if n > 0 => Ok(Cli::Set(Command::Profile(n - 1))),
+                    _ => Err(ParseCliError(format!("Invalid profile: {}", p))),
+                },
+                _ => Err(ParseCliError("One profile expected".to_string())),
+            },
+            "debounce" => match params.as_slice() {
+                [p] => Ok(Cli::Set(Command::Debounce(p.parse().map_err(|_| {
+                    ParseCliError(format!("Invalid debounce time: {}", p))
+                })?))),
+                _ => Err(ParseCliError("One debounce time expected".to_string())),
+            },
+            "sleep" => match params.as_slice() {
+                [p] => Ok(Cli::Set(Command::SleepTime(parse_duration(p)?))),
+                _ => Err(ParseCliError("One duration expected".to_string())),
+            },
+            "lod" => match params.as_slice() {
+                [p] => Ok(Cli::Set(Command::Lod(p.parse::<Lod>().map_err(|_| {
+                    ParseCliError(format!("Invalid lift-off distance: {}", p))
+                })?))),
+                _ => Err(ParseCliError("One lift-off distance expected".to_string())),
+            },
+            "feature" => match params.as_slice() {
+                [name, state] => Ok(Cli::Set(Command::Feature(
+                    name.parse::<Feature>()
+                        .map_err(|_| ParseCliError(format!("Unknown feature: {}", name)))?,
+                    parse_switch(state)?,
+                ))),
+                _ => Err(ParseCliError(
+                    "A feature and on or off expected".to_string(),
+                )),
+            },
+            "scroll" => match params.as_slice() {
+                [setting, v] => {
+                    let level = || {
+                        v.parse::<u8>().map_err(|_| {
+                            ParseCliError(format!("Invalid scroll {}: {}", setting, v))
+                        })
+                    };
+                    match setting.as_str() {
+                        "speed" => Ok(Cli::Set(Command::ScrollSpeed(level()?))),
+                        "inertia" => Ok(Cli::Set(Command::ScrollInertia(level()?))),
+                        "lines" => Ok(Cli::Set(Command::ScrollLines(level()?))),
+                        "direction" => match v.to_lowercase().as_str() {
+                            "natural" => Ok(Cli::Set(Command::ScrollNatural(true))),
+                            "standard" => Ok(Cli::Set(Command::ScrollNatural(false))),
+                            _ => Err(ParseCliError(format!("Invalid scroll direction: {}", v))),
+                        },
+                        _ => Err(ParseCliError(format!(
+                            "Unknown scroll setting: {}",
+                            setting
+                        ))),
+                    }
+                }
+                _ => Err(ParseCliError(
+                    "A scroll setting and a value expected".to_string(),
+                )),
+            },
+            "wake" => match params.as_slice() {
+                [source, state] => Ok(Cli::Set(Command::WakeSource(
+                    source
+                        .parse::<WakeSource>()
+                        .map_err(|_| ParseCliError(format!("Unknown wake source: {}", source)))?,
+                    parse_switch(state)?,
+                ))),
+                _ => Err(ParseCliError(
+                    "A wake source and on or off expected".to_string(),
+                )),
+            },
+            #[cfg(any(test, feature = "simulate"))]
+            "button" => {
+                let parse_button = |b: &String| {
+                    b.parse::<Button>()
+                        .map_err(|_| ParseCliError(format!("Unknown button: {}", b)))
+                };
+                match params.as_slice() {
+                    [b] => Ok(Cli::Button(parse_button(b)?)),
+                    [b, action] => Ok(Cli::Set(Command::Button(
+                        parse_button(b)?,
+                        action.parse::<ButtonAction>().map_err(|_| {
+                            ParseCliError(format!("Invalid button action: {}", action))
+                        })?,
+                    ))),
+                    _ => Err(ParseCliError(
+                        "A button and an optional action expected".to_string(),
+                    )),
+                }
+            }
+            #[cfg(any(test, feature = "simulate"))]
+            "macro" => {
+                let [slot, path] = params.as_slice() else {
+                    return Err(ParseCliError("A slot and a file expected".to_string()));
+                };
+                let slot = match slot.parse::<u8>() {
+                    Ok(n) if n > 0 => n - 1,
+                    _ => return Err(ParseCliError(format!("Invalid macro slot: {}", slot))),
+                };
+                Ok(Cli::Macro(slot, path.clone()))
+            }
+            _ => Err(ParseCliError(format!("Unknown command: {}", cmd))),
+        }
+    }
+}
+
+pub async fn run(cli: Cli) -> Result<(), Box<dyn error::Error + Send + Sync>> {
+    match cli {
+        Cli::Tray => Ok(()),
+        Cli::Help => {
+            println!("{}{}", USAGE, USAGE_SIMULATE);
+            Ok(())
+        }
+        Cli::Info => {
+            let (_, _, report_rx) = connect().await?;
+            print_report(&report_rx.borrow());
+            Ok(())
+        }
+        #[cfg(any(test, feature = "simulate"))]
+        Cli::Button(button) => {
+            let (keychron_hid, dev, mut report_rx) = connect().await?;
+            keychron_hid.request_buttons(&dev, &[button])?;
+            let index = u8::from(button) as usize;
+            wait_report(&mut report_rx, |r| r.buttons[index].is_some()).await?;
+            if let Some(action) = report_rx.borrow().buttons[index] {
+                println!("{}: {}", button, action);
+            }
+            Ok(())
+        }
+        #[cfg(any(test, feature = "simulate"))]
+        Cli::Macro(slot, path) => {
+            let m: Macro = std::fs::read_to_string(&path)
+                .map_err(|e| format!("Cannot read {}: {}", path, e))?
+                .parse()?;
+            let (keychron_hid, dev, _) = connect().await?;
+            keychron_hid.upload_macro(&dev, slot, &m)?;
+            println!("Macro {} stored ({} events)", slot + 1, m.events.len());
+            Ok(())
+        }
+        Cli::CheckUpdate => {
+            let (_, _, report_rx) = connect().await?;
+            let (vendor_product_id, current) = {
+                let r = report_rx.borrow();
+                (r.vendor_product_id(), r.fr_version_string())
+            };
+            let release =
+                task::spawn_blocking(move || update::latest_firmware(vendor_product_id)).await??;
+            match release {
+                Some(release) if update::is_newer(&release.version, &current) => {
+                    println!(
+                        "Firmware {} available (installed {})",
+                        release.version, current
+                    );
+                    if let Some(changelog) = release.changelog {
+                        println!("{}", changelog);
+                    }
+                    println!("{}", release.path);
+                }
+                _ => println!("Firmware {} is up to date", current),
+            }
+            Ok(())
+        }
+        #[cfg(any(test, feature = "simulate"))]
+        Cli::Set(Command::Pair) => {
+            let keychron_hid = KeychronHid::new()?;
+            let receiver = keychron_hid
+                .list_compatible_devices()?
+                .into_iter()
+                .find(KeychronHid::is_receiver)
+                .ok_or("No receiver found")?;
+            println!("Switch the mouse to 2.4G and hold its pairing button");
+            keychron_hid.pair(&receiver, |left| {
+                print!("\r{} s left ", left.as_secs());
+                io::stdout().flush().ok();
+            })?;
+            println!("\nMouse paired");
+            Ok(())
+        }
+        #[cfg(any(test, feature = "simulate"))]
+        Cli::Set(Command::Button(button, action)) => {
+            let (keychron_hid, dev, mut report_rx) = connect().await?;
+            let report = *report_rx.borrow_and_update();
+            keychron_hid.send_command(&dev, &report, Command::Button(button, action))?;
+            let index = u8::from(button) as usize;
+            wait_report(&mut report_rx, |r| r.buttons[index].is_some()).await?;
+            if let Some(action) = report_rx.borrow().buttons[index] {
+                println!("{}: {}", button, action);
+            }
+            Ok(())
+        }
+        Cli::Set(cmd) => {
+            let (keychron_hid, dev, mut report_rx) = connect().await?;
+            let report = *report_rx.borrow_and_update();
+            keychron_hid.send_command(&dev, &report, cmd)?;
+            // the device answers with its updated state, other reports may come first
+            wait_report(&mut report_rx, |r| applied(cmd, &report, r)).await?;
+            print_report(&report_rx.borrow());
+            Ok(())
+        }
+    }
+}
+
+fn parse_switch(s: &str) -> Result<bool, ParseCliError> {
+    match s.to_lowercase().as_str() {
+        "on" | "true" | "1" => Ok(true),
+        "off" | "false" | "0" => Ok(false),
+        _ => Err(ParseCliError(format!("Expected on or off: {}", s))),
+    }
+}
+
+// Duration in whole minutes, minutes without a unit
+fn parse_duration(s: &str) -> Result<u16, ParseCliError> {
+    let err = || ParseCliError(format!("Invalid duration: {}", s));
+    let s_lc = s.replace(" ", "").to_lowercase();
+    let unit_pos = s_lc
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(s_lc.len());
+    let (v, unit) = s_lc.split_at(unit_pos);
+    let v: u16 = v.parse().map_err(|_| err())?;
+    match unit {
+        "s" | "sec" if v.is_multiple_of(60) => Ok(v / 60),
+        "" | "m" | "min" => Ok(v),
+        "h" => v.checked_mul(60).ok_or_else(err),
+        _ => Err(err()),
+    }
+}
+
+// Whether the report shows the setting changed by the command, `before` is the
+// report the command was sent with
+fn applied(cmd: Command, before: &Report, r: &Report) -> bool {
+    match cmd {
+        Command::DpiLevel(level) => r.current_settings().is_some_and(|s| s.dpi_level == level),
+        Command::DpiLevels(levels, level_num) => {
+            r.dpi.level_num == level_num
+                && r.dpi.levels_val.get(..level_num as usize) == levels.get(..level_num as usize)
+        }
+        Command::PollingRate(rate) => r.current_polling_rate() == Some(rate),
+        Command::Profile(profile) => r.profile.current == profile,
+        Command::Debounce(debounce) => r.debounce.value == debounce,
+        Command::SleepTime(time) => r.sleep.time == time,
+        Command::Lod(lod) => r.sys_features.lod == Some(lod),
+        Command::Feature(feature, enable) => r.features().contains(&(feature, enable)),
+        Command::ScrollSpeed(speed) => r.scroll.speed == speed,
+        Command::ScrollInertia(inertia) => r.scroll.inertia == inertia,
+        Command::ScrollLines(lines) => r.scroll.spl == lines,
+        Command::ScrollNatural(scroll_dir) => r.sys_features.scroll_dir == scroll_dir,
+        Command::WakeSource(source, enable) => r.wake_sources().contains(&(source, enable)),
+        Command::ToggleFeature(_) => r.features() != before.features(),
+        Command::ToggleScrollNatural => r.sys_features.scroll_dir != before.sys_features.scroll_dir,
+        Command::ToggleWakeSource(_) => r.wake_sources() != before.wake_sources(),
+        Command::RestoreDefault(DefaultSetting::DpiLevels) | Command::RestoreDefaults => r
+            .keychron_device()
+            .ok()
+            .and_then(|kd| kd.descriptor())
+            .is_some_and(|d| {
+                r.dpi.level_num as usize == d.dpi_levels.len() && r.dpi.levels_val == d.dpi_levels
+            }),
+        // confirmed by their own reports
+        #[cfg(any(test, feature = "simulate"))]
+        Command::Button(..) | Command::Pair => true,
+    }
+}
+
+// Without a console of its own in release builds on Windows, the output goes to
+// the one the command was started from
+#[cfg(windows)]
+pub fn attach_console() {
+    const ATTACH_PARENT_PROCESS: u32 = u32::MAX;
+    #[link(name = "kernel32")]
+    unsafe extern "system" {
+        fn AttachConsole(process_id: u32) -> i32;
+    }
+    // SAFETY: no arguments to check, failing only leaves the output unseen as before
+    unsafe {
+        AttachConsole(ATTACH_PARENT_PROCESS);
+    }
+}
+
+async fn connect()
+-> Result<(KeychronHid, DeviceInfo, watch::Receiver<Report>), Box<dyn error::Error + Send + Sync>> {
+    let keychron_hid = KeychronHid::new()?;
+    let dev = keychron_hid
+        .list_compatible_devices()?
+        .into_iter()
+        .next()
+        .ok_or("No compatible device found")?;
+    let (mut report_rx, _) = keychron_hid.listen(&dev)?;
+    keychron_hid.poke_device(&dev)?;
+    wait_report(&mut report_rx, Report::is_populated).await?;
+    Ok((keychron_hid, dev, report_rx))
+}
+
+async fn wait_report(
+    report_rx: &mut watch::Receiver<Report>,
+    f: impl Fn(&Report) -> bool,
+) -> Result<(), Box<dyn error::Error + Send + Sync>> {
+    time::timeout(REPORT_TIMEOUT, async {
+        loop {
+            report_rx.changed().await?;
+            if f(&report_rx.borrow_and_update()) {
+                return Ok::<(), Box<dyn error::Error + Send + Sync>>(());
+            }
+        }
+    })
+    .await
+    .map_err(|_| "Timed out waiting for the device to report")?
+}
+
+fn print_report(r: &Report) {
+    println!(
+        "{} {}",
+        match r.keychron_device() {
+            Ok(kd) => kd.to_string(),
+            Err(_) => format!("{:04x}:{:04x}", r.vid, r.pid),
+        },
+        r.fr_version_string()
+    );
+    if let Some(rc) = r.receiver {
+        println!(
+            "Receiver: {} {}",
+            match rc.keychron_device() {
+                Ok(kd) => kd.to_string(),
+                Err(_) => format!("{:04x}", rc.pid),
+            },
+            rc.fr_version_string()
+        );
+    }
+    println!(
+        "Battery: {}%{}",
+        r.power.value,
+        if r.power.state { " (charging)" } else { "" }
+    );
+    if r.support.profile_support {
+        println!("Profile: {}/{}", r.profile.current + 1, r.profile.level_num);
+    }
+    let level_num = (r.dpi.level_num as usize).min(r.dpi.levels_val.len());
+    println!(
+        "DPI: {}",
+        r.dpi.levels_val[..level_num]
+            .iter()
+            .enumerate()
+            .map(|(i, v)| {
+                if r.current_settings()
+                    .is_some_and(|s| i == s.dpi_level as usize)
+                {
+                    format!("[{}]", v)
+                } else {
+                    v.to_string()
+                }
+            })
+            .collect::<Vec<String>>()
+            .join(" ")
+    );
+    println!(
+        "Polling rate: {}",
+        r.polling_rate
+            .rates()
+            .iter()
+            .enumerate()
+            .map(|(i, v)| {
+                if r.current_settings()
+                    .is_some_and(|s| i == s.polling_rate_level as usize)
+                {
+                    format!("[{}]", v.hz())
+                } else {
+                    v.hz().to_string()
+                }
+            })
+            .collect::<Vec<String>>()
+            .join(" ")
+    );
+    if r.support.debounce_support {
+        println!(
+            "Debounce: {}",
+            r.debounce
+                .options()
+                .iter()
+                .map(|&v| if v == r.debounce.value {
+                    format!("[{} ms]", v)
+                } else {
+                    format!("{} ms", v)
+                })
+                .collect::<Vec<String>>()
+                .join(" ")
+        );
+    }
+    if let Some(lod) = r.sys_features.lod {
+        println!("LOD: {}", lod);
+    }
+    for (feature, enabled) in r.features() {
+        println!("{}: {}", feature, if enabled { "on" } else { "off" });
+    }
+    println!(
+        "Scroll direction: {}",
+        if r.sys_features.scroll_dir {
+            "natural"
+        } else {
+            "standard"
+        }
+    );
+    if r.support.scroll_support {
+        println!(
+            "Scroll: speed {}, inertia {}, {} lines per notch",
+            r.scroll.speed, r.scroll.inertia, r.scroll.spl
+        );
+    }
+    if r.sleep_known() {
+        println!("Sleep: {}", r.sleep);
+    }
+    for (source, enabled) in r.wake_sources() {
+        println!(
+            "Wake up on {}: {}",
+            source.to_string().to_lowercase(),
+            if enabled { "on" } else { "off" }
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations_parsed_as_minutes() {
+        assert_eq!(parse_duration("5"), Ok(5));
+        assert_eq!(parse_duration("5 min"), Ok(5));
+        assert_eq!(parse_duration("10m"), Ok(10));
+        assert_eq!(parse_duration("1h"), Ok(60));
+        assert_eq!(parse_duration("120s"), Ok(2));
+        assert!(parse_duration("90s").is_err());
+        assert!(parse_duration("1d").is_err());
+        assert!(parse_duration("min").is_err());
+        assert!(parse_duration("2000h").is_err());
+    }
+}
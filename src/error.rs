@@ -0,0 +1,134 @@
+use std::{error, fmt, io};
+
+use hidapi::HidError;
+
+#[derive(Debug)]
+pub enum KeychronError {
+    // No access to the device, on Linux the udev rules are missing
+    PermissionDenied,
+    // The device was unplugged or reset
+    DeviceGone,
+    // The mouse behind the receiver is off or asleep
+    DeviceAsleep,
+    // A report of this type could not be parsed
+    ProtocolError { report_type: u8 },
+    // The device sent a report type the parser does not know
+    UnknownReport { report_type: u8 },
+    // A read returned only the report id
+    EmptyReport,
+    // A request could not be sent to the device
+    Write(HidError),
+    Hid(HidError),
+    Notification(notify_rust::error::Error),
+}
+
+impl KeychronError {
+    // Errors after which the device is to be looked for again
+    pub fn is_reconnectable(&self) -> bool {
+        matches!(
+            self,
+            KeychronError::DeviceGone | KeychronError::DeviceAsleep
+        )
+    }
+
+    pub fn write(e: HidError) -> Self {
+        match KeychronError::from(e) {
+            KeychronError::Hid(e) => KeychronError::Write(e),
+            e => e,
+        }
+    }
+}
+
+impl fmt::Display for KeychronError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeychronError::PermissionDenied => {
+                f.write_str("Permission denied to access the device")
+            }
+            KeychronError::DeviceGone => f.write_str("Device disconnected"),
+            KeychronError::DeviceAsleep => f.write_str("Device asleep"),
+            KeychronError::ProtocolError { report_type } => {
+                write!(f, "Invalid report of type {}", report_type)
+            }
+            KeychronError::UnknownReport { report_type } => {
+                write!(f, "Unknown report type {}", report_type)
+            }
+            KeychronError::EmptyReport => f.write_str("Empty report"),
+            KeychronError::Write(e) => write!(f, "Failed to write to the device: {}", e),
+            KeychronError::Hid(e) => write!(f, "{}", e),
+            KeychronError::Notification(e) => write!(f, "Failed to show notification: {}", e),
+        }
+    }
+}
+
+impl error::Error for KeychronError {
+    fn description(&self) -> &str {
+        match self {
+            KeychronError::PermissionDenied => "permission denied",
+            KeychronError::DeviceGone => "device gone",
+            KeychronError::DeviceAsleep => "device asleep",
+            KeychronError::ProtocolError { .. } => "protocol error",
+            KeychronError::UnknownReport { .. } => "unknown report",
+            KeychronError::EmptyReport => "empty report",
+            KeychronError::Write(_) => "write error",
+            KeychronError::Hid(_) => "hid error",
+            KeychronError::Notification(_) => "notification error",
+        }
+    }
+}
+
+impl From<HidError> for KeychronError {
+    fn from(e: HidError) -> Self {
+        if let HidError::IoError { error } = &e {
+            match error.kind() {
+                io::ErrorKind::PermissionDenied => return KeychronError::PermissionDenied,
+                io::ErrorKind::NotFound | io::ErrorKind::BrokenPipe => {
+                    return KeychronError::DeviceGone;
+                }
+                _ => (),
+            }
+        }
+        KeychronError::Hid(e)
+    }
+}
+
+impl From<notify_rust::error::Error> for KeychronError {
+    fn from(e: notify_rust::error::Error) -> Self {
+        KeychronError::Notification(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hid_errors_classified() {
+        let io = |kind| HidError::IoError {
+            error: io::Error::from(kind),
+        };
+        let api = |message: &str| HidError::HidApiError {
+            message: message.to_string(),
+        };
+        assert!(matches!(
+            io(io::ErrorKind::PermissionDenied).into(),
+            KeychronError::PermissionDenied
+        ));
+        assert!(matches!(
+            io(io::ErrorKind::NotFound).into(),
+            KeychronError::DeviceGone
+        ));
+        // messages are not looked into
+        let denied = "Failed to open a device with path '/dev/hidraw3': Permission denied";
+        assert!(matches!(api(denied).into(), KeychronError::Hid(_)));
+        assert!(matches!(
+            api("Input/output error").into(),
+            KeychronError::Hid(_)
+        ));
+        assert!(matches!(
+            KeychronError::write(api("hid_error")),
+            KeychronError::Write(_)
+        ));
+        assert!(KeychronError::write(io(io::ErrorKind::BrokenPipe)).is_reconnectable());
+    }
+}
@@ -0,0 +1,310 @@
+use std::{ffi::CString, io};
+#[cfg(target_os = "linux")]
+use std::{
+    mem,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    ptr,
+};
+
+#[cfg(target_os = "linux")]
+use tokio::io::{Interest, unix::AsyncFd};
+
+#[cfg(target_os = "linux")]
+use crate::keychron_hid::KEYCHRON_VENDOR_ID;
+
+// Events sent by udev once its rules ran, so the permissions are already set
+#[cfg(target_os = "linux")]
+const UDEV_MONITOR_GROUP: u32 = 2;
+#[cfg(target_os = "linux")]
+const UDEV_HEADER_PREFIX: &[u8] = b"libudev\0";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+pub enum HotplugEvent {
+    // A device was added or changed, e.g. got new permissions
+    Added,
+    // The device with this path is gone
+    Removed(CString),
+}
+
+// Watches the hidraw devices of Keychron coming and going, only on Linux for now
+pub struct Hotplug {
+    #[cfg(target_os = "linux")]
+    socket: AsyncFd<OwnedFd>,
+}
+
+impl Hotplug {
+    #[cfg(not(target_os = "linux"))]
+    pub fn new() -> io::Result<Self> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn new() -> io::Result<Self> {
+        // SAFETY: plain socket calls, the fd is owned right after its creation
+        let socket = unsafe {
+            let fd = libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_DGRAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                libc::NETLINK_KOBJECT_UEVENT,
+            );
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let socket = OwnedFd::from_raw_fd(fd);
+            // have the sender's credentials passed along, to only trust udev
+            let on: libc::c_int = 1;
+            if libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_PASSCRED,
+                &on as *const libc::c_int as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            ) < 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+            let mut addr: libc::sockaddr_nl = mem::zeroed();
+            addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+            addr.nl_groups = UDEV_MONITOR_GROUP;
+            if libc::bind(
+                socket.as_raw_fd(),
+                &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            ) < 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+            socket
+        };
+        Ok(Hotplug {
+            socket: AsyncFd::with_interest(socket, Interest::READABLE)?,
+        })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub async fn next(&self) -> io::Result<HotplugEvent> {
+        std::future::pending().await
+    }
+
+    // Wait for the next event about a Keychron device
+    #[cfg(target_os = "linux")]
+    pub async fn next(&self) -> io::Result<HotplugEvent> {
+        let mut buf = [0u8; 8192];
+        loop {
+            let received = self
+                .socket
+                .async_io(Interest::READABLE, |socket| {
+                    recv_from_root(socket.as_raw_fd(), &mut buf)
+                })
+                .await?;
+            // anyone else could fake a device coming or going
+            if let Some(len) = received
+                && let Some(event) = parse_uevent(&buf[..len])
+            {
+                return Ok(event);
+            }
+        }
+    }
+}
+
+// Receive a message, its length only if it was sent by root like udev does
+#[cfg(target_os = "linux")]
+fn recv_from_root(fd: RawFd, buf: &mut [u8]) -> io::Result<Option<usize>> {
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    // room for the credentials, aligned like a cmsghdr
+    let mut control = [0u64; 8];
+    // SAFETY: the buffers outlive the call and their lengths are passed along, the
+    // control messages are walked with the libc macros within the returned length
+    unsafe {
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = mem::size_of_val(&control) as _;
+        let len = libc::recvmsg(fd, &mut msg, 0);
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_CREDENTIALS
+            {
+                let cred = ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::ucred);
+                return Ok((cred.uid == 0).then_some(len as usize));
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    Ok(None)
+}
+
+// The properties are NUL separated "KEY=value" pairs, udev puts a header before them
+#[cfg(target_os = "linux")]
+pub fn parse_uevent(msg: &[u8]) -> Option<HotplugEvent> {
+    let msg = match msg.strip_prefix(UDEV_HEADER_PREFIX) {
+        Some(header) => {
+            // the offset of the properties follows the magic and the header size
+            let offset = header.get(8..12)?;
+            msg.get(u32::from_ne_bytes(offset.try_into().ok()?) as usize..)?
+        }
+        None => msg,
+    };
+    let mut action = None;
+    let mut devpath = None;
+    let mut devname = None;
+    let mut subsystem = None;
+    for prop in msg.split(|&b| b == 0) {
+        let Ok(prop) = std::str::from_utf8(prop) else {
+            continue;
+        };
+        match prop.split_once('=') {
+            Some(("ACTION", v)) => action = Some(v),
+            Some(("DEVPATH", v)) => devpath = Some(v),
+            Some(("DEVNAME", v)) => devname = Some(v),
+            Some(("SUBSYSTEM", v)) => subsystem = Some(v),
+            _ => (),
+        }
+    }
+    if subsystem != Some("hidraw") || !devpath.is_some_and(is_keychron_devpath) {
+        return None;
+    }
+    match action? {
+        "add" | "change" => Some(HotplugEvent::Added),
+        "remove" => {
+            // udev gives the full path, the kernel one relative to /dev
+            let devname = devname?;
+            let path = if devname.starts_with('/') {
+                devname.to_string()
+            } else {
+                format!("/dev/{}", devname)
+            };
+            CString::new(path).ok().map(HotplugEvent::Removed)
+        }
+        _ => None,
+    }
+}
+
+// The HID device in the path is named bus:vendor:product.instance, e.g. 0003:3434:D049.0005
+#[cfg(target_os = "linux")]
+fn is_keychron_devpath(devpath: &str) -> bool {
+    devpath.split('/').any(|c| {
+        let mut ids = c.split(':');
+        matches!(
+            (ids.next(), ids.next(), ids.next(), ids.next()),
+            (Some(_), Some(vendor), Some(_), None)
+                if u16::from_str_radix(vendor, 16) == Ok(KEYCHRON_VENDOR_ID)
+        )
+    })
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    const DEVPATH: &str = "DEVPATH=/devices/pci0000:00/0000:00:14.0/usb1/1-2/1-2:1.2/0003:3434:D049.0005/hidraw/hidraw3";
+
+    fn uevent(props: &[&str]) -> Vec<u8> {
+        // udev header: prefix, magic, header size, properties offset and length, filters
+        let mut msg = UDEV_HEADER_PREFIX.to_vec();
+        msg.extend_from_slice(&0xfeedcafeu32.to_be_bytes());
+        msg.extend_from_slice(&40u32.to_ne_bytes());
+        msg.extend_from_slice(&40u32.to_ne_bytes());
+        msg.extend_from_slice(&0u32.to_ne_bytes());
+        // a bloom filter right before the properties
+        msg.extend_from_slice(&[0xff; 16]);
+        for p in props {
+            msg.extend_from_slice(p.as_bytes());
+            msg.push(0);
+        }
+        msg
+    }
+
+    #[test]
+    fn keychron_hidraw_events() {
+        assert_eq!(
+            parse_uevent(&uevent(&["ACTION=add", DEVPATH, "SUBSYSTEM=hidraw"])),
+            Some(HotplugEvent::Added)
+        );
+        assert_eq!(
+            parse_uevent(&uevent(&[
+                "ACTION=remove",
+                DEVPATH,
+                "SUBSYSTEM=hidraw",
+                "DEVNAME=/dev/hidraw3"
+            ])),
+            Some(HotplugEvent::Removed(CString::new("/dev/hidraw3").unwrap()))
+        );
+        assert_eq!(
+            parse_uevent(&uevent(&[
+                "ACTION=remove",
+                DEVPATH,
+                "SUBSYSTEM=hidraw",
+                "DEVNAME=hidraw3"
+            ])),
+            Some(HotplugEvent::Removed(CString::new("/dev/hidraw3").unwrap()))
+        );
+        // as sent by the kernel, without header
+        let kernel = format!("add@/hidraw3\0ACTION=add\0{}\0SUBSYSTEM=hidraw\0", DEVPATH);
+        assert_eq!(parse_uevent(kernel.as_bytes()), Some(HotplugEvent::Added));
+    }
+
+    #[test]
+    fn only_root_trusted() {
+        for passcred in [false, true] {
+            let mut fds = [0; 2];
+            // SAFETY: plain socket calls, the fds are owned right after their creation
+            let (_tx, rx) = unsafe {
+                assert_eq!(
+                    libc::socketpair(libc::AF_UNIX, libc::SOCK_DGRAM, 0, fds.as_mut_ptr()),
+                    0
+                );
+                let (tx, rx) = (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1]));
+                let on = passcred as libc::c_int;
+                libc::setsockopt(
+                    rx.as_raw_fd(),
+                    libc::SOL_SOCKET,
+                    libc::SO_PASSCRED,
+                    &on as *const libc::c_int as *const libc::c_void,
+                    mem::size_of::<libc::c_int>() as libc::socklen_t,
+                );
+                libc::send(tx.as_raw_fd(), b"x".as_ptr() as *const libc::c_void, 1, 0);
+                (tx, rx)
+            };
+            let mut buf = [0u8; 8];
+            // without credentials nobody is trusted
+            let root = passcred && unsafe { libc::getuid() } == 0;
+            assert_eq!(
+                recv_from_root(rx.as_raw_fd(), &mut buf).unwrap(),
+                root.then_some(1)
+            );
+        }
+    }
+
+    #[test]
+    fn other_events_ignored() {
+        // another vendor
+        assert_eq!(
+            parse_uevent(&uevent(&[
+                "ACTION=add",
+                "DEVPATH=/devices/usb1/1-3/1-3:1.0/0003:046D:C52B.0001/hidraw/hidraw0",
+                "SUBSYSTEM=hidraw"
+            ])),
+            None
+        );
+        // the parent HID device
+        assert_eq!(
+            parse_uevent(&uevent(&[
+                "ACTION=add",
+                "DEVPATH=/devices/usb1/1-2/1-2:1.2/0003:3434:D049.0005",
+                "SUBSYSTEM=hid"
+            ])),
+            None
+        );
+        assert_eq!(parse_uevent(&[]), None);
+        assert_eq!(parse_uevent(UDEV_HEADER_PREFIX), None);
+    }
+}
@@ -138,4 +138,24 @@ impl KeychronDevice {
             | KeychronDevice::M6_8K => KeychronDeviceCategory::Mouse,
         }
     }
+
+    // Launcher descriptor of the model, only known for the ones in doc/README.md
+    pub fn descriptor(&self) -> Option<Descriptor> {
+        match *self {
+            KeychronDevice::M6_8K => Some(Descriptor {
+                dpi_limit: (50, 30000),
+                dpi_levels: [400, 800, 1600, 3200, 5000],
+            }),
+            _ => None,
+        }
+    }
+}
+
+// What the launcher descriptor of a model tells about it
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Hash)]
+pub struct Descriptor {
+    // "dpi.limit", lowest and highest DPI
+    pub dpi_limit: (u16, u16),
+    // "dpi.level", default DPI of the levels
+    pub dpi_levels: [u16; 5],
 }
@@ -1,108 +1,794 @@
+#[cfg(any(test, feature = "simulate"))]
 use crate::{
+    button::{Button, ButtonAction},
+    macros::{MACRO_NUM, Macro, MacroError},
+    report::{PairState, REPORT_TYPE_BUTTON, REPORT_TYPE_MACRO, REPORT_TYPE_PAIR},
+};
+use crate::{
+    error::KeychronError,
     keychron_device::{KeychronDevice, KeychronDeviceCategory},
-    report::{Report, TryMerge},
+    report::{
+        Feature, InvalidDpiError, Lod, PollingRate, REPORT_TYPE_FULL, REPORT_TYPE_PROFILE, Report,
+        ReportSysFeatures, SCROLL_INERTIA_MAX, SCROLL_LINES_MAX, SCROLL_SPEED_MAX, TryMerge,
+        WakeSource,
+    },
+    transport::{DeviceInfo, HidTransport, Transport, TransportDevice},
+};
+use std::{
+    env,
+    error::Error,
+    future::Future,
+    pin::Pin,
+    sync::{
+        Arc, Mutex, PoisonError,
+        atomic::{AtomicBool, Ordering},
+    },
+    task::{Context, Poll},
+    time::Duration,
 };
-use hidapi::{BusType, DeviceInfo, HidApi, HidError};
-use std::error::Error;
 use tokio::{sync::watch, task};
 
 pub const KEYCHRON_VENDOR_ID: u16 = 0x3434;
 pub const KEYCHRON_USAGE: u16 = 0x1;
 pub const KEYCHRON_USAGE_PAGE: u16 = 0xffc1;
 
+// Runs against a simulated device instead of the connected ones when set,
+// only in builds with the "simulate" feature
+#[cfg(feature = "simulate")]
+pub const SIMULATE_ENV: &str = "KEYCHRON_SIMULATE";
+
+// Writes go to the connected devices only when set, see `CMD_SET`
+pub const ALLOW_WRITES_ENV: &str = "KEYCHRON_ALLOW_WRITES";
+const WRITES_NOT_ALLOWED: &str =
+    "Writing to the device is not verified yet, set KEYCHRON_ALLOW_WRITES to try it";
+
+// GET and INFO are the requests `poke_device` always sent to have the device report.
+// SET is assumed, no capture of the launcher confirms it: it is taken to be the write
+// counterpart of GET, patching the report type's layout with
+// [CMD_SET, type, offset, len, data...]. The report types written and the layouts
+// of buttons, macros and pairing are assumed the same way, see `report`. So writes
+// to hardware stay off unless `ALLOW_WRITES_ENV` is set.
+pub const CMD_GET: u8 = 179;
+pub const CMD_SET: u8 = 180;
+pub const CMD_INFO: u8 = 181;
+
+#[cfg(any(test, feature = "simulate"))]
+const PAIR_TIMEOUT: Duration = Duration::from_secs(30);
+// How often a listening read looks whether it was cancelled
+const LISTEN_POLL: Duration = Duration::from_millis(100);
+
+// Settings that can be written to the device
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Command {
+    DpiLevel(u8),
+    // DPI values of the first `n` levels and the level count `n`
+    DpiLevels([u16; 5], u8),
+    PollingRate(PollingRate),
+    Profile(u8),
+    Debounce(u8),
+    // Sleep timeout in minutes
+    SleepTime(u16),
+    Lod(Lod),
+    Feature(Feature, bool),
+    // Flip a feature from its state when the command runs
+    ToggleFeature(Feature),
+    ScrollSpeed(u8),
+    ScrollInertia(u8),
+    ScrollLines(u8),
+    // Natural (reversed) scroll direction
+    ScrollNatural(bool),
+    ToggleScrollNatural,
+    WakeSource(WakeSource, bool),
+    ToggleWakeSource(WakeSource),
+    // Only built with the "simulate" feature, see `CMD_SET`
+    #[cfg(any(test, feature = "simulate"))]
+    Button(Button, ButtonAction),
+    // Pair a new mouse to the receiver, only built with the "simulate" feature
+    #[cfg(any(test, feature = "simulate"))]
+    Pair,
+    // Restore a setting to the default of the launcher descriptor
+    RestoreDefault(DefaultSetting),
+    // Restore all the settings with a known default, only the DPI levels so far
+    RestoreDefaults,
+}
+
+// Settings the launcher descriptor gives a default for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DefaultSetting {
+    DpiLevels,
+}
+
+pub const DEFAULT_SETTINGS: [DefaultSetting; 1] = [DefaultSetting::DpiLevels];
+
+// The reads of a device, they hold it open until they end
+pub struct ListenHandle {
+    task: task::JoinHandle<Result<(), KeychronError>>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl ListenHandle {
+    // Stop reading and wait until the device is closed
+    pub async fn cancel(self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+        // a finished task may already have handed out its result
+        if !self.task.is_finished() {
+            self.task.await.ok();
+        }
+    }
+}
+
+impl Future for ListenHandle {
+    type Output = Result<Result<(), KeychronError>, task::JoinError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.task).poll(cx)
+    }
+}
+
+// Shareable between the tasks of all the connected devices
 pub struct KeychronHid {
-    hid_api: HidApi,
+    transport: Mutex<Box<dyn Transport>>,
+    allow_writes: bool,
 }
 
 impl KeychronHid {
-    pub fn new() -> Result<Self, HidError> {
-        HidApi::disable_device_discovery();
-        let hid_api = HidApi::new()?;
-        Ok(KeychronHid { hid_api })
+    pub fn new() -> Result<Self, KeychronError> {
+        #[cfg(feature = "simulate")]
+        if env::var_os(SIMULATE_ENV).is_some() {
+            return Ok(Self::with_transport(Box::new(
+                crate::scripted::ScriptedTransport::simulated(),
+            )));
+        }
+        Ok(KeychronHid {
+            allow_writes: env::var_os(ALLOW_WRITES_ENV).is_some(),
+            ..Self::with_transport(Box::new(HidTransport::new()?))
+        })
+    }
+
+    // Writes are allowed on a transport given explicitly, like the scripted one
+    pub fn with_transport(transport: Box<dyn Transport>) -> Self {
+        KeychronHid {
+            transport: Mutex::new(transport),
+            allow_writes: true,
+        }
+    }
+
+    fn check_writes(&self) -> Result<(), &'static str> {
+        if self.allow_writes {
+            Ok(())
+        } else {
+            Err(WRITES_NOT_ALLOWED)
+        }
+    }
+
+    fn open(&self, dev: &DeviceInfo) -> Result<Box<dyn TransportDevice>, KeychronError> {
+        self.transport
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .open(dev)
+            .map_err(KeychronError::from)
     }
 
     // Lists the compatible Keychron devices
-    pub fn list_compatible_devices(&mut self) -> Result<Vec<&DeviceInfo>, HidError> {
-        self.hid_api.reset_devices()?;
-        self.hid_api.add_devices(KEYCHRON_VENDOR_ID, 0)?;
-        let devs: Vec<&DeviceInfo> = self
-            .hid_api
-            .device_list()
+    pub fn list_compatible_devices(&self) -> Result<Vec<DeviceInfo>, KeychronError> {
+        let devs = self
+            .transport
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .enumerate(KEYCHRON_VENDOR_ID)?
+            .into_iter()
             .filter(|d| {
-                d.bus_type() as u8 == BusType::Usb as u8
-                    && d.usage() == KEYCHRON_USAGE
-                    && d.usage_page() == KEYCHRON_USAGE_PAGE
-                    && TryInto::<KeychronDevice>::try_into(d.product_id()).is_ok()
+                d.usb
+                    && d.usage == KEYCHRON_USAGE
+                    && d.usage_page == KEYCHRON_USAGE_PAGE
+                    && TryInto::<KeychronDevice>::try_into(d.product_id).is_ok()
             })
             .collect();
-        if devs.len() < 2 {
-            Ok(devs)
-        } else {
-            let devs_f: Vec<&DeviceInfo> = devs
-                .iter()
-                .filter(|d| {
-                    TryInto::<KeychronDevice>::try_into(d.product_id())
-                        .is_ok_and(|kd| kd.device_type() != KeychronDeviceCategory::Receiver)
-                })
-                .cloned()
-                .collect();
-            if !devs_f.is_empty() {
-                Ok(devs_f)
-            } else {
-                Ok(devs)
+        Ok(devs)
+    }
+
+    // Whether the device is a receiver rather than a mouse
+    pub fn is_receiver(dev: &DeviceInfo) -> bool {
+        TryInto::<KeychronDevice>::try_into(dev.product_id)
+            .is_ok_and(|kd| kd.device_type() == KeychronDeviceCategory::Receiver)
+    }
+
+    // Put the receiver in pairing mode and wait for a mouse to pair, `progress` gets
+    // the time left every second. Pairing is assumed to be a setting of its own,
+    // written and reported back as a `PairState` (see `CMD_SET`).
+    #[cfg(any(test, feature = "simulate"))]
+    pub fn pair(
+        &self,
+        dev: &DeviceInfo,
+        mut progress: impl FnMut(Duration),
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.check_writes()?;
+        if !Self::is_receiver(dev) {
+            return Err("Only a receiver pairs mice".into());
+        }
+        let hid_dev = self.open(dev)?;
+        Self::write_setting(&hid_dev, REPORT_TYPE_PAIR, 1, &[PairState::Pairing.into()])?;
+        let mut buf = [0u8; 64];
+        let mut remaining = PAIR_TIMEOUT;
+        while !remaining.is_zero() {
+            progress(remaining);
+            let step = remaining.min(Duration::from_secs(1));
+            remaining -= step;
+            let buf_size = hid_dev.read_timeout(&mut buf, step.as_millis() as i32)?;
+            // skip the reportId, other reports may arrive meanwhile
+            if buf_size < 3 || buf[1] != REPORT_TYPE_PAIR {
+                continue;
+            }
+            match PairState::try_from(buf[2]) {
+                Ok(PairState::Paired) => return Ok(()),
+                Ok(PairState::Failed) => return Err("Pairing failed".into()),
+                _ => (),
             }
         }
+        Self::write_setting(&hid_dev, REPORT_TYPE_PAIR, 1, &[PairState::Idle.into()])?;
+        Err("No mouse paired in time".into())
     }
 
     pub fn listen(
         &self,
         dev: &DeviceInfo,
-    ) -> Result<
-        (
-            watch::Receiver<Report>,
-            task::JoinHandle<Result<(), Box<dyn Error + Send + Sync>>>,
-        ),
-        HidError,
-    > {
-        let hid_dev_read = dev.open_device(&self.hid_api)?;
+    ) -> Result<(watch::Receiver<Report>, ListenHandle), KeychronError> {
+        let hid_dev_read = self.open(dev)?;
         let (tx, rx) = watch::channel(Report::default());
-        let handle = task::spawn_blocking(move || {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let cancelled2 = cancelled.clone();
+        let task = task::spawn_blocking(move || {
             let mut buf = [0u8; 64];
             let mut r = Report::default();
             loop {
-                let buf_size = match hid_dev_read.read(&mut buf) {
-                    Ok(s) => s,
-                    Err(e) => {
-                        return Err(e.into());
-                    }
-                };
+                let buf_size =
+                    match hid_dev_read.read_timeout(&mut buf, LISTEN_POLL.as_millis() as i32) {
+                        Ok(s) => s,
+                        // the device opened fine, so it is gone or being reset
+                        Err(_) => return Err(KeychronError::DeviceGone),
+                    };
+                // cancelled or nobody is interested anymore
+                if cancelled2.load(Ordering::Relaxed) || tx.is_closed() {
+                    return Ok(());
+                }
                 if buf_size == 0 {
                     continue;
                 }
-                // strip reportId from buffer
-                r.merge(&buf[1..buf_size])?;
-                tx.send(r)?;
+                // strip reportId from buffer, a garbled or unknown report is
+                // skipped, the next one will do
+                if r.merge(&buf[1..buf_size]).is_err() {
+                    continue;
+                }
+                if tx.send(r).is_err() {
+                    return Ok(());
+                }
             }
         });
-        Ok((rx, handle))
+        Ok((rx, ListenHandle { task, cancelled }))
     }
 
     // Poke the device to have it report its status
-    pub fn poke_device(&self, dev: &DeviceInfo) -> Result<(), HidError> {
-        let hid_dev_write = dev.open_device(&self.hid_api)?;
-        {
-            let mut req_info = [0u8; 21];
-            req_info[0] = 181;
-            req_info[1] = 2;
-            req_info[2] = 1;
-            hid_dev_write.write(&req_info)?;
+    pub fn poke_device(&self, dev: &DeviceInfo) -> Result<(), KeychronError> {
+        let hid_dev_write = self.open(dev)?;
+        Self::request_info(&hid_dev_write, KeychronDeviceCategory::Receiver)?;
+        if Self::is_receiver(dev) {
+            Self::request_info(&hid_dev_write, KeychronDeviceCategory::Mouse)?;
         }
-        {
-            let mut req_full = [0u8; 64];
-            req_full[0] = 179;
-            req_full[1] = 6;
-            hid_dev_write.write(&req_full)?;
+        Self::request_report(&hid_dev_write, REPORT_TYPE_FULL)?;
+        Ok(())
+    }
+
+    // Request the actions assigned to the buttons, they come back as new reports
+    #[cfg(any(test, feature = "simulate"))]
+    pub fn request_buttons(
+        &self,
+        dev: &DeviceInfo,
+        buttons: &[Button],
+    ) -> Result<(), KeychronError> {
+        let hid_dev_write = self.open(dev)?;
+        for &button in buttons {
+            Self::request_button(&hid_dev_write, button)?;
         }
         Ok(())
     }
+
+    // Store a macro in one of the device's slots, bind it with `ButtonAction::Macro`
+    #[cfg(any(test, feature = "simulate"))]
+    pub fn upload_macro(
+        &self,
+        dev: &DeviceInfo,
+        slot: u8,
+        m: &Macro,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if slot >= MACRO_NUM {
+            return Err(MacroError::Slot(slot).into());
+        }
+        self.check_writes()?;
+        let data = m.to_bytes()?;
+        let hid_dev_write = self.open(dev)?;
+        // the storage is patched in chunks, as settings are with a slot added:
+        // [CMD_SET, type, slot, offset, len, data...], assumed like `CMD_SET`
+        for (i, chunk) in data.chunks(58).enumerate() {
+            let mut req_set = [0u8; 64];
+            req_set[0] = CMD_SET;
+            req_set[1] = REPORT_TYPE_MACRO;
+            req_set[2] = slot;
+            req_set[3] = (i * 58) as u8;
+            req_set[4] = chunk.len() as u8;
+            req_set[5..5 + chunk.len()].copy_from_slice(chunk);
+            hid_dev_write
+                .write(&req_set)
+                .map_err(KeychronError::write)?;
+        }
+        Ok(())
+    }
+
+    // Write a setting to the device, the updated state comes back as a new report
+    pub fn send_command(
+        &self,
+        dev: &DeviceInfo,
+        report: &Report,
+        cmd: Command,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.check_writes()?;
+        // no reset command is known, only the defaults the descriptor has are restored
+        if cmd == Command::RestoreDefaults {
+            for setting in DEFAULT_SETTINGS {
+                self.send_command(dev, report, Command::RestoreDefault(setting))?;
+            }
+            self.poke_device(dev)?;
+            return Ok(());
+        }
+        // pairing opens the receiver itself and reads its answers
+        #[cfg(any(test, feature = "simulate"))]
+        if cmd == Command::Pair {
+            return self.pair(dev, |_| ());
+        }
+        let hid_dev_write = self.open(dev)?;
+        // toggles from a menu built before the last report apply to the current state
+        let cmd = match cmd {
+            Command::RestoreDefault(DefaultSetting::DpiLevels) => {
+                let descriptor = report
+                    .keychron_device()
+                    .ok()
+                    .and_then(|kd| kd.descriptor())
+                    .ok_or("No default settings known for the device")?;
+                let levels = descriptor.dpi_levels;
+                Command::DpiLevels(levels, levels.len() as u8)
+            }
+            Command::ToggleFeature(feature) => {
+                let (_, enabled) = report
+                    .features()
+                    .into_iter()
+                    .find(|&(f, _)| f == feature)
+                    .ok_or(format!("{} not supported by the device", feature))?;
+                Command::Feature(feature, !enabled)
+            }
+            Command::ToggleScrollNatural => Command::ScrollNatural(!report.sys_features.scroll_dir),
+            Command::ToggleWakeSource(source) => {
+                let (_, enabled) = report
+                    .wake_sources()
+                    .into_iter()
+                    .find(|&(ws, _)| ws == source)
+                    .ok_or(format!("{} wake up not supported by the device", source))?;
+                Command::WakeSource(source, !enabled)
+            }
+            cmd => cmd,
+        };
+        match cmd {
+            Command::DpiLevel(level) => {
+                if level >= report.dpi.level_num {
+                    return Err(format!("DPI level {} out of range", level).into());
+                }
+                // dpi level and polling rate level share a byte per profile
+                let profile = report
+                    .profile_index()
+                    .ok_or("Active profile out of range")?;
+                let data = (report.profiles[profile].polling_rate_level << 4) | (level & 15);
+                Self::write_setting(&hid_dev_write, REPORT_TYPE_FULL, 2 + profile as u8, &[data])?;
+            }
+            Command::DpiLevels(levels, level_num) => {
+                let levels = levels
+                    .get(..level_num as usize)
+                    .ok_or(InvalidDpiError::LevelNum(level_num as usize))?;
+                report.validate_dpi_levels(levels)?;
+                // keep the values of unused levels as they are
+                let mut levels_val = report.dpi.levels_val;
+                levels_val[..levels.len()].copy_from_slice(levels);
+                let data: Vec<u8> = levels_val.iter().flat_map(|v| v.to_le_bytes()).collect();
+                Self::write_setting(&hid_dev_write, REPORT_TYPE_FULL, 5, &data)?;
+                Self::write_setting(&hid_dev_write, REPORT_TYPE_FULL, 16, &[level_num])?;
+            }
+            Command::PollingRate(rate) => {
+                let level = report
+                    .polling_rate
+                    .rates()
+                    .iter()
+                    .position(|&r| r == rate)
+                    .ok_or(format!("Polling rate {} not supported by the device", rate))?;
+                let profile = report
+                    .profile_index()
+                    .ok_or("Active profile out of range")?;
+                let data = ((level as u8) << 4) | (report.profiles[profile].dpi_level & 15);
+                Self::write_setting(&hid_dev_write, REPORT_TYPE_FULL, 2 + profile as u8, &[data])?;
+            }
+            Command::Profile(profile) => {
+                if !report.support.profile_support || profile >= report.profile.level_num {
+                    return Err(
+                        format!("Profile {} not supported by the device", profile + 1).into(),
+                    );
+                }
+                Self::write_setting(&hid_dev_write, REPORT_TYPE_PROFILE, 1, &[profile])?;
+            }
+            Command::Debounce(debounce) => {
+                if !report.support.debounce_support
+                    || !report.debounce.options().contains(&debounce)
+                {
+                    return Err(
+                        format!("Debounce of {} ms not supported by the device", debounce).into(),
+                    );
+                }
+                Self::write_setting(&hid_dev_write, REPORT_TYPE_FULL, 17, &[debounce])?;
+            }
+            Command::SleepTime(time) => {
+                if !report.can_set_sleep() {
+                    return Err("Sleep time not supported by the device".into());
+                }
+                if time == 0 || time > u8::MAX as u16 {
+                    return Err(
+                        format!("Sleep time must be between 1 and {} minutes", u8::MAX).into(),
+                    );
+                }
+                Self::write_setting(&hid_dev_write, REPORT_TYPE_FULL, 18, &[time as u8])?;
+            }
+            Command::Lod(lod) => {
+                let sys_features = ReportSysFeatures {
+                    lod: Some(lod),
+                    ..report.sys_features
+                };
+                Self::write_sys_features(&hid_dev_write, report, &sys_features)?;
+            }
+            Command::Feature(Feature::AngleSnapping, line) => {
+                let sys_features = ReportSysFeatures {
+                    line,
+                    ..report.sys_features
+                };
+                Self::write_sys_features(&hid_dev_write, report, &sys_features)?;
+            }
+            Command::Feature(Feature::RippleControl, wave) => {
+                let sys_features = ReportSysFeatures {
+                    wave,
+                    ..report.sys_features
+                };
+                Self::write_sys_features(&hid_dev_write, report, &sys_features)?;
+            }
+            Command::Feature(Feature::MotionSync, motion) => {
+                let sys_features = ReportSysFeatures {
+                    motion,
+                    ..report.sys_features
+                };
+                Self::write_sys_features(&hid_dev_write, report, &sys_features)?;
+            }
+            Command::Feature(Feature::Fps20k, enable) => {
+                if !report.support.fps20k_support {
+                    return Err("20K FPS not supported by the device".into());
+                }
+                let sys_features = ReportSysFeatures {
+                    fps20k: enable,
+                    ..report.sys_features
+                };
+                Self::write_setting(
+                    &hid_dev_write,
+                    REPORT_TYPE_FULL,
+                    52,
+                    &[sys_features.fps20k_flags()],
+                )?;
+            }
+            Command::ScrollSpeed(speed) => {
+                if !report.support.scroll_support || !(1..=SCROLL_SPEED_MAX).contains(&speed) {
+                    return Err(
+                        format!("Scroll speed {} not supported by the device", speed).into(),
+                    );
+                }
+                Self::write_setting(&hid_dev_write, REPORT_TYPE_FULL, 27, &[speed])?;
+            }
+            Command::ScrollInertia(inertia) => {
+                if !report.support.scroll_support || inertia > SCROLL_INERTIA_MAX {
+                    return Err(
+                        format!("Scroll inertia {} not supported by the device", inertia).into(),
+                    );
+                }
+                Self::write_setting(&hid_dev_write, REPORT_TYPE_FULL, 28, &[inertia])?;
+            }
+            Command::ScrollLines(lines) => {
+                if !report.support.scroll_support || !(1..=SCROLL_LINES_MAX).contains(&lines) {
+                    return Err(format!(
+                        "Scrolling {} lines per notch not supported by the device",
+                        lines
+                    )
+                    .into());
+                }
+                Self::write_setting(&hid_dev_write, REPORT_TYPE_FULL, 29, &[lines])?;
+            }
+            Command::ScrollNatural(scroll_dir) => {
+                let sys_features = ReportSysFeatures {
+                    scroll_dir,
+                    ..report.sys_features
+                };
+                Self::write_sys_features(&hid_dev_write, report, &sys_features)?;
+            }
+            // turned into the commands they stand for above
+            #[cfg(any(test, feature = "simulate"))]
+            Command::Pair => (),
+            Command::ToggleFeature(_)
+            | Command::ToggleScrollNatural
+            | Command::ToggleWakeSource(_)
+            | Command::RestoreDefault(_)
+            | Command::RestoreDefaults => (),
+            #[cfg(any(test, feature = "simulate"))]
+            Command::Button(button, action) => {
+                if let ButtonAction::Macro(slot) = action
+                    && slot >= MACRO_NUM
+                {
+                    return Err(MacroError::Slot(slot).into());
+                }
+                // buttons are addressed by index rather than offset
+                Self::write_setting(
+                    &hid_dev_write,
+                    REPORT_TYPE_BUTTON,
+                    button.into(),
+                    &action.to_bytes(),
+                )?;
+                Self::request_button(&hid_dev_write, button)?;
+                return Ok(());
+            }
+            Command::WakeSource(source, enable) => {
+                if !report.wake_sources().iter().any(|(ws, _)| *ws == source) {
+                    return Err(format!("{} wake up not supported by the device", source).into());
+                }
+                let mut rouse_origin = report.rouse_origin;
+                match source {
+                    WakeSource::Key => rouse_origin.key = enable,
+                    WakeSource::Scroll => rouse_origin.scroll = enable,
+                    WakeSource::Move => rouse_origin.mmove = enable,
+                    WakeSource::SideScroll => rouse_origin.side_scroll = enable,
+                }
+                Self::write_setting(
+                    &hid_dev_write,
+                    REPORT_TYPE_FULL,
+                    51,
+                    &[rouse_origin.flags()],
+                )?;
+            }
+        }
+        // the device answers with its updated settings
+        Self::request_report(&hid_dev_write, REPORT_TYPE_FULL)?;
+        Ok(())
+    }
+
+    // Patch `data` at `offset` of the given report type's layout
+    fn write_setting(
+        hid_dev: &dyn TransportDevice,
+        report_type: u8,
+        offset: u8,
+        data: &[u8],
+    ) -> Result<(), KeychronError> {
+        let mut req_set = [0u8; 64];
+        req_set[0] = CMD_SET;
+        req_set[1] = report_type;
+        req_set[2] = offset;
+        req_set[3] = data.len() as u8;
+        req_set[4..4 + data.len()].copy_from_slice(data);
+        hid_dev.write(&req_set).map_err(KeychronError::write)?;
+        Ok(())
+    }
+
+    // Sensor features are written in the layout the device reports them in
+    fn write_sys_features(
+        hid_dev: &dyn TransportDevice,
+        report: &Report,
+        sys_features: &ReportSysFeatures,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let layout = report
+            .sys_features_layout()
+            .ok_or("Sensor settings not supported by the device")?;
+        Self::write_setting(hid_dev, layout, 15, &[sys_features.flags()])?;
+        Ok(())
+    }
+
+    #[cfg(any(test, feature = "simulate"))]
+    fn request_button(hid_dev: &dyn TransportDevice, button: Button) -> Result<(), KeychronError> {
+        let mut req = [0u8; 64];
+        req[0] = CMD_GET;
+        req[1] = REPORT_TYPE_BUTTON;
+        req[2] = button.into();
+        hid_dev.write(&req).map_err(KeychronError::write)?;
+        Ok(())
+    }
+
+    // Asks for a description by the ttype of the category. Every device describes
+    // itself for the receiver ttype, the one always sent before. A receiver is
+    // assumed to forward the mouse ttype to the mouse paired with it, no capture
+    // of a receiver confirms it.
+    fn request_info(
+        hid_dev: &dyn TransportDevice,
+        category: KeychronDeviceCategory,
+    ) -> Result<(), KeychronError> {
+        let mut req_info = [0u8; 21];
+        req_info[0] = CMD_INFO;
+        req_info[1] = category.ttype();
+        req_info[2] = 1;
+        hid_dev.write(&req_info).map_err(KeychronError::write)?;
+        Ok(())
+    }
+
+    fn request_report(hid_dev: &dyn TransportDevice, report_type: u8) -> Result<(), KeychronError> {
+        let mut req = [0u8; 64];
+        req[0] = CMD_GET;
+        req[1] = report_type;
+        hid_dev.write(&req).map_err(KeychronError::write)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scripted::ScriptedTransport;
+
+    #[test]
+    fn writes_need_opt_in() {
+        let keychron_hid = KeychronHid {
+            allow_writes: false,
+            ..KeychronHid::with_transport(Box::new(ScriptedTransport::simulated()))
+        };
+        let dev = keychron_hid.list_compatible_devices().unwrap().remove(0);
+        let e = keychron_hid
+            .send_command(&dev, &Report::default(), Command::DpiLevel(0))
+            .unwrap_err();
+        assert_eq!(e.to_string(), WRITES_NOT_ALLOWED);
+        assert!(keychron_hid.poke_device(&dev).is_ok());
+    }
+
+    async fn wait_report(
+        report_rx: &mut watch::Receiver<Report>,
+        f: impl Fn(&Report) -> bool,
+    ) -> Report {
+        tokio::time::timeout(Duration::from_secs(2), async {
+            loop {
+                report_rx.changed().await.unwrap();
+                let r = *report_rx.borrow_and_update();
+                if f(&r) {
+                    return r;
+                }
+            }
+        })
+        .await
+        .unwrap()
+    }
+
+    // The first simulated device, listened to and poked until it has reported
+    async fn connected() -> (KeychronHid, DeviceInfo, watch::Receiver<Report>, Report) {
+        let keychron_hid = KeychronHid::with_transport(Box::new(ScriptedTransport::simulated()));
+        let dev = keychron_hid.list_compatible_devices().unwrap().remove(0);
+        let (mut report_rx, _) = keychron_hid.listen(&dev).unwrap();
+        keychron_hid.poke_device(&dev).unwrap();
+        let r = wait_report(&mut report_rx, Report::is_populated).await;
+        (keychron_hid, dev, report_rx, r)
+    }
+
+    #[tokio::test]
+    async fn poke_and_write_scripted_device() {
+        let (keychron_hid, dev, mut report_rx, r) = connected().await;
+        assert_eq!(r.keychron_device(), Ok(KeychronDevice::M6_8K));
+        assert_eq!(r.fr_version_string(), "1.0.1");
+        assert_eq!(r.current_dpi(), 800);
+        assert_eq!(r.current_polling_rate(), Some(PollingRate::Hz1000));
+
+        keychron_hid
+            .send_command(&dev, &r, Command::DpiLevel(4))
+            .unwrap();
+        let r = wait_report(&mut report_rx, |r| r.current_dpi() != 800).await;
+        assert_eq!(r.current_dpi(), 6400);
+        assert_eq!(r.current_polling_rate(), Some(PollingRate::Hz1000));
+    }
+
+    #[tokio::test]
+    async fn dpi_level_written_to_current_profile() {
+        let (keychron_hid, dev, mut report_rx, r) = connected().await;
+        assert!(
+            keychron_hid
+                .send_command(&dev, &r, Command::DpiLevel(r.dpi.level_num))
+                .is_err()
+        );
+
+        keychron_hid
+            .send_command(&dev, &r, Command::DpiLevel(0))
+            .unwrap();
+        let r = wait_report(&mut report_rx, |r| r.current_dpi() != 800).await;
+        assert_eq!(r.profiles[0].dpi_level, 0);
+        // the polling rate sharing the byte and the other profiles are left alone
+        assert_eq!(r.profiles[0].polling_rate_level, 2);
+        assert_eq!(r.profiles[1], r.profiles[2]);
+        assert_eq!(r.profiles[1].dpi_level, 1);
+    }
+
+    #[tokio::test]
+    async fn feature_toggled_from_current_state() {
+        let (keychron_hid, dev, mut report_rx, r) = connected().await;
+        let motion = r.sys_features.motion;
+        for _ in 0..2 {
+            let r = *report_rx.borrow_and_update();
+            keychron_hid
+                .send_command(&dev, &r, Command::ToggleFeature(Feature::MotionSync))
+                .unwrap();
+            wait_report(&mut report_rx, |n| {
+                n.sys_features.motion != r.sys_features.motion
+            })
+            .await;
+        }
+        let r = *report_rx.borrow();
+        assert_eq!(r.sys_features.motion, motion);
+        // the bits around the feature flags are written back as reported
+        assert_eq!(r.sys_features.flags(), r.sys_features.flags_raw);
+    }
+
+    #[tokio::test]
+    async fn defaults_restored_from_descriptor() {
+        let (keychron_hid, dev, mut report_rx, r) = connected().await;
+        keychron_hid
+            .send_command(&dev, &r, Command::DpiLevels([1000, 2000, 0, 0, 0], 2))
+            .unwrap();
+        let r = wait_report(&mut report_rx, |r| r.dpi.level_num == 2).await;
+        keychron_hid
+            .send_command(&dev, &r, Command::RestoreDefaults)
+            .unwrap();
+        let r = wait_report(&mut report_rx, |r| r.dpi.level_num == 5).await;
+        assert_eq!(r.dpi.levels_val, [400, 800, 1600, 3200, 5000]);
+    }
+
+    #[tokio::test]
+    async fn listening_cancelled() {
+        let keychron_hid = KeychronHid::with_transport(Box::new(ScriptedTransport::simulated()));
+        let dev = keychron_hid.list_compatible_devices().unwrap().remove(0);
+        let (_report_rx, listen_handle) = keychron_hid.listen(&dev).unwrap();
+        tokio::time::timeout(Duration::from_secs(2), listen_handle.cancel())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn receiver_and_mouse_described_separately() {
+        let keychron_hid = KeychronHid::with_transport(Box::new(ScriptedTransport::simulated()));
+        let devs = keychron_hid.list_compatible_devices().unwrap();
+        assert_eq!(devs.len(), 2);
+        let dev = devs
+            .into_iter()
+            .find(|d| d.product_id == u16::from(KeychronDevice::UltraLink8K))
+            .unwrap();
+        let (mut report_rx, _) = keychron_hid.listen(&dev).unwrap();
+        keychron_hid.poke_device(&dev).unwrap();
+        let r = wait_report(&mut report_rx, |r| r.is_populated() && r.receiver.is_some()).await;
+        assert_eq!(r.keychron_device(), Ok(KeychronDevice::M6_4K));
+        let rc = r.receiver.unwrap();
+        assert_eq!(rc.keychron_device(), Ok(KeychronDevice::UltraLink8K));
+        assert_eq!(rc.fr_version_string(), "1.0.1");
+    }
+
+    #[test]
+    fn pairing_targets_the_receiver() {
+        let keychron_hid = KeychronHid::with_transport(Box::new(ScriptedTransport::simulated()));
+        let (receivers, mice): (Vec<DeviceInfo>, Vec<DeviceInfo>) = keychron_hid
+            .list_compatible_devices()
+            .unwrap()
+            .into_iter()
+            .partition(KeychronHid::is_receiver);
+        assert_eq!((receivers.len(), mice.len()), (1, 1));
+        let mut progress = vec![];
+        keychron_hid
+            .pair(&receivers[0], |left| progress.push(left))
+            .unwrap();
+        assert_eq!(progress, [PAIR_TIMEOUT]);
+        assert!(keychron_hid.pair(&mice[0], |_| ()).is_err());
+    }
 }
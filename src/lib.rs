@@ -0,0 +1,5 @@
+pub mod button;
+pub mod error;
+pub mod keychron_device;
+pub mod packet;
+pub mod report;
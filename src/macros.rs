@@ -0,0 +1,241 @@
+use std::error;
+use std::fmt;
+use std::str;
+
+use crate::button::{MouseButton, key_code, key_name, mouse_button};
+
+// Slot count and size, event codes and their 2 parameter bytes of the macro
+// storage, assumed like `REPORT_TYPE_MACRO`. Keys are HID keyboard usages and
+// buttons the HID button bits, as in button actions.
+pub const MACRO_NUM: u8 = 4;
+// Bytes of device storage per macro slot, including the end marker
+pub const MACRO_SIZE: usize = 255;
+
+const EVENT_END: u8 = 0;
+const EVENT_KEY_DOWN: u8 = 1;
+const EVENT_KEY_UP: u8 = 2;
+const EVENT_BUTTON_DOWN: u8 = 3;
+const EVENT_BUTTON_UP: u8 = 4;
+const EVENT_DELAY: u8 = 5;
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Hash)]
+pub enum MacroEvent {
+    // HID keyboard usage
+    KeyDown(u8),
+    KeyUp(u8),
+    ButtonDown(MouseButton),
+    ButtonUp(MouseButton),
+    // Milliseconds
+    Delay(u16),
+}
+
+impl MacroEvent {
+    // Event type followed by its 2 parameter bytes
+    pub fn to_bytes(self) -> [u8; 3] {
+        match self {
+            MacroEvent::KeyDown(code) => [EVENT_KEY_DOWN, code, 0],
+            MacroEvent::KeyUp(code) => [EVENT_KEY_UP, code, 0],
+            MacroEvent::ButtonDown(mb) => [EVENT_BUTTON_DOWN, mb.into(), 0],
+            MacroEvent::ButtonUp(mb) => [EVENT_BUTTON_UP, mb.into(), 0],
+            MacroEvent::Delay(ms) => {
+                let ms = ms.to_le_bytes();
+                [EVENT_DELAY, ms[0], ms[1]]
+            }
+        }
+    }
+}
+
+impl fmt::Display for MacroEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let key = |code: u8| match key_name(code) {
+            Some(name) => name.to_lowercase(),
+            None => format!("{:#04x}", code),
+        };
+        let button = |mb: MouseButton| format!("mouse:{}", format!("{:?}", mb).to_lowercase());
+        match *self {
+            MacroEvent::KeyDown(code) => write!(f, "down {}", key(code)),
+            MacroEvent::KeyUp(code) => write!(f, "up {}", key(code)),
+            MacroEvent::ButtonDown(mb) => write!(f, "down {}", button(mb)),
+            MacroEvent::ButtonUp(mb) => write!(f, "up {}", button(mb)),
+            MacroEvent::Delay(ms) => write!(f, "delay {}", ms),
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Hash, Default)]
+pub struct Macro {
+    pub events: Vec<MacroEvent>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Hash)]
+pub enum MacroError {
+    // Line number and content that could not be parsed
+    Parse(usize, String),
+    TooLong(usize),
+    // Slot counted from 0
+    Slot(u8),
+}
+
+impl fmt::Display for MacroError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MacroError::Parse(line, s) => write!(f, "Invalid macro line {}: {}", line, s),
+            MacroError::TooLong(len) => write!(
+                f,
+                "Macro needs {} bytes, only {} fit on the device",
+                len, MACRO_SIZE
+            ),
+            MacroError::Slot(slot) => write!(
+                f,
+                "Invalid macro slot {}, the device has {}",
+                u16::from(*slot) + 1,
+                MACRO_NUM
+            ),
+        }
+    }
+}
+
+impl error::Error for MacroError {
+    fn description(&self) -> &str {
+        match self {
+            MacroError::Parse(..) => "invalid macro line",
+            MacroError::TooLong(_) => "macro too long",
+            MacroError::Slot(_) => "invalid macro slot",
+        }
+    }
+}
+
+impl str::FromStr for Macro {
+    type Err = MacroError;
+
+    // One event per line: `down <KEY>`, `up <KEY>`, `press <KEY>` or `delay <MS>`,
+    // where a key is either a key name or `mouse:<BUTTON>`. `#` starts a comment.
+    fn from_str(s: &str) -> ::std::result::Result<Self, Self::Err> {
+        let mut events = Vec::new();
+        for (i, line) in s.lines().enumerate() {
+            let l = line.split('#').next().unwrap_or("").trim();
+            if l.is_empty() {
+                continue;
+            }
+            let err = || MacroError::Parse(i + 1, line.to_string());
+            let (action, arg) = l.split_once(char::is_whitespace).ok_or_else(err)?;
+            let arg = arg.replace([' ', '-', '_'], "").to_uppercase();
+            let (down, up) = match arg.strip_prefix("MOUSE:") {
+                Some(name) => {
+                    let mb = mouse_button(name).ok_or_else(err)?;
+                    (MacroEvent::ButtonDown(mb), MacroEvent::ButtonUp(mb))
+                }
+                None if action.eq_ignore_ascii_case("delay") => {
+                    events.push(MacroEvent::Delay(arg.parse().map_err(|_| err())?));
+                    continue;
+                }
+                None => {
+                    let code = key_code(&arg).ok_or_else(err)?;
+                    (MacroEvent::KeyDown(code), MacroEvent::KeyUp(code))
+                }
+            };
+            match action.to_lowercase().as_str() {
+                "down" => events.push(down),
+                "up" => events.push(up),
+                "press" => events.extend([down, up]),
+                _ => return Err(err()),
+            }
+        }
+        Ok(Macro { events })
+    }
+}
+
+impl fmt::Display for Macro {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for event in &self.events {
+            writeln!(f, "{}", event)?;
+        }
+        Ok(())
+    }
+}
+
+impl Macro {
+    // Events as stored on the device, terminated by an end marker
+    pub fn to_bytes(&self) -> Result<Vec<u8>, MacroError> {
+        let mut bytes: Vec<u8> = self.events.iter().flat_map(|e| e.to_bytes()).collect();
+        bytes.push(EVENT_END);
+        if bytes.len() > MACRO_SIZE {
+            return Err(MacroError::TooLong(bytes.len()));
+        }
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MACRO: &str = "# copy
+press ctrl # held below
+down Ctrl
+press c
+up ctrl
+delay 20
+press mouse:left
+up 0x64
+";
+
+    #[test]
+    fn macro_parsed() {
+        let m: Macro = MACRO.parse().unwrap();
+        assert_eq!(
+            m.events,
+            [
+                MacroEvent::KeyDown(0xe0),
+                MacroEvent::KeyUp(0xe0),
+                MacroEvent::KeyDown(0xe0),
+                MacroEvent::KeyDown(0x06),
+                MacroEvent::KeyUp(0x06),
+                MacroEvent::KeyUp(0xe0),
+                MacroEvent::Delay(20),
+                MacroEvent::ButtonDown(MouseButton::Left),
+                MacroEvent::ButtonUp(MouseButton::Left),
+                MacroEvent::KeyUp(0x64),
+            ]
+        );
+        assert_eq!("".parse(), Ok(Macro::default()));
+        for (s, line) in [("press a\nhold b", 2), ("delay", 1), ("delay 1s", 1)] {
+            assert_eq!(
+                s.parse::<Macro>(),
+                Err(MacroError::Parse(
+                    line,
+                    s.lines().nth(line - 1).unwrap().to_string()
+                ))
+            );
+        }
+    }
+
+    #[test]
+    fn macro_displayed_as_parsed() {
+        let m: Macro = MACRO.parse().unwrap();
+        assert_eq!(m.to_string().parse(), Ok(m));
+    }
+
+    #[test]
+    fn macro_encoded() {
+        let m: Macro = "press a\ndelay 300".parse().unwrap();
+        assert_eq!(
+            m.to_bytes(),
+            Ok(vec![
+                EVENT_KEY_DOWN,
+                0x04,
+                0,
+                EVENT_KEY_UP,
+                0x04,
+                0,
+                EVENT_DELAY,
+                44,
+                1,
+                EVENT_END
+            ])
+        );
+        // 3 bytes per event and the end marker
+        let m: Macro = ("press a\n".repeat(42) + "delay 1").parse().unwrap();
+        assert_eq!(m.to_bytes(), Err(MacroError::TooLong(MACRO_SIZE + 1)));
+    }
+}
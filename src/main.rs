@@ -1,125 +1,449 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")] // hide console window on Windows in release
-use tokio::{sync::Mutex, time};
-
-use crate::{keychron_hid::KeychronHid, tray::Tray};
-use std::{error::Error, sync::Arc, time::Duration};
-
-mod keychron_device;
+mod cli;
+mod hotplug;
 mod keychron_hid;
-mod report;
+#[cfg(any(test, feature = "simulate"))]
+mod macros;
+#[cfg(any(test, feature = "simulate"))]
+mod scripted;
+mod transport;
 mod tray;
 #[cfg(target_os = "linux")]
 mod udev;
+mod update;
+
+use tokio::{
+    sync::{Mutex, Notify, mpsc, watch},
+    task::{self, JoinSet},
+    time,
+};
+
+use crate::{
+    cli::Cli,
+    error::KeychronError,
+    hotplug::{Hotplug, HotplugEvent},
+    keychron_device::KeychronDevice,
+    keychron_hid::{Command, KeychronHid, ListenHandle},
+    report::Report,
+    transport::DeviceInfo,
+    tray::Tray,
+    update::FirmwareRelease,
+};
+#[cfg(any(test, feature = "simulate"))]
+use keychron_tray_rs::button;
+use keychron_tray_rs::{error, keychron_device, report};
+use std::{collections::HashMap, error::Error, sync::Arc, time::Duration};
 
 const DEVICE_CHECK_PERIOD: Duration = Duration::from_secs(5);
+const UPDATE_CHECK_PERIOD: Duration = Duration::from_secs(24 * 3600);
+
+// Last firmware check and its result per vendor_product_id, kept across reconnects
+type UpdateChecks = Arc<Mutex<HashMap<u32, (time::Instant, Option<FirmwareRelease>)>>>;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let tray_app = Arc::new(Mutex::new(Tray::new()?));
-    loop {
-        {
-            let mut tray_app_lock = tray_app.lock().await;
-            tray_app_lock.clear().await;
+    #[cfg(windows)]
+    if std::env::args().len() > 1 {
+        cli::attach_console();
+    }
+    let cli = Cli::parse(std::env::args().skip(1))?;
+    if cli != Cli::Tray {
+        return cli::run(cli).await.map_err(|e| e.to_string().into());
+    }
+    let (cmd_tx, mut cmd_rx) = mpsc::channel::<(usize, Command)>(8);
+    let tray_app = Arc::new(Mutex::new(Tray::new(cmd_tx)?));
+    let keychron_hid = Arc::new(KeychronHid::new()?);
+    // route the tray commands to the task of their device
+    let dev_cmd_txs: Arc<Mutex<HashMap<usize, mpsc::Sender<Command>>>> = Default::default();
+    let dev_cmd_txs2 = dev_cmd_txs.clone();
+    tokio::spawn(async move {
+        while let Some((id, cmd)) = cmd_rx.recv().await {
+            let tx = dev_cmd_txs2.lock().await.get(&id).cloned();
+            if let Some(tx) = tx {
+                tx.send(cmd).await.ok();
+            }
         }
-        let (keychron_hid, mut report_rx, listen_handle, dev) = loop {
-            let mut keychron_hid = KeychronHid::new()?;
-            let dev = loop {
-                let devs = keychron_hid.list_compatible_devices()?;
-                if devs.is_empty() {
-                    time::sleep(DEVICE_CHECK_PERIOD).await;
-                    continue;
-                }
-                break devs[0].clone();
-            };
+    });
+    // without hotplug events, look for devices periodically
+    let mut hotplug = Hotplug::new().ok();
+    let mut device_tasks = JoinSet::new();
+    // signals the task of a device that it was unplugged
+    let mut connected: HashMap<DeviceInfo, Arc<Notify>> = HashMap::new();
+    // the device of each task, to know which one a failed task was for
+    let mut task_devs: HashMap<task::Id, DeviceInfo> = HashMap::new();
+    // devices that failed and when, they are tried again after a check period
+    let mut failed: HashMap<DeviceInfo, time::Instant> = HashMap::new();
+    #[cfg(target_os = "linux")]
+    let mut udev_rules_notified = false;
+    let mut next_id = 0;
+    let update_checks = UpdateChecks::default();
+    loop {
+        failed.retain(|_, at| at.elapsed() < DEVICE_CHECK_PERIOD);
+        // the other devices keep running while one fails, it is tried again later
+        let (devs, mut retry) = match keychron_hid.list_compatible_devices() {
+            Ok(devs) => (devs, !failed.is_empty()),
+            Err(_) => (vec![], true),
+        };
+        for dev in devs {
+            if connected.contains_key(&dev) || failed.contains_key(&dev) {
+                continue;
+            }
             match keychron_hid.listen(&dev) {
-                Ok((r, l)) => break (keychron_hid, r, l, dev),
-                Err(e) => {
+                Ok(listening) => {
                     #[cfg(target_os = "linux")]
-                    if e.to_string()
-                        .to_lowercase()
-                        .find("permission denied")
-                        .is_some()
                     {
                         let mut tray_app_lock = tray_app.lock().await;
-                        tray_app_lock.needs_udev_rules(true).await;
-                        if !tray_app_lock.notify_udev_rules().await.is_ok_and(|r| r) {
-                            time::sleep(DEVICE_CHECK_PERIOD).await;
+                        tray_app_lock.needs_udev_rules(false).await;
+                        udev_rules_notified = false;
+                    }
+                    let id = next_id;
+                    next_id += 1;
+                    let (dev_cmd_tx, dev_cmd_rx) = mpsc::channel::<Command>(8);
+                    dev_cmd_txs.lock().await.insert(id, dev_cmd_tx);
+                    let gone = Arc::new(Notify::new());
+                    connected.insert(dev.clone(), gone.clone());
+                    let keychron_hid = keychron_hid.clone();
+                    let tray_app = tray_app.clone();
+                    let dev_cmd_txs = dev_cmd_txs.clone();
+                    let update_checks = update_checks.clone();
+                    let update_handle = tokio::spawn(check_firmware(
+                        id,
+                        listening.0.clone(),
+                        tray_app.clone(),
+                        update_checks,
+                    ));
+                    let task_dev = dev.clone();
+                    let task = device_tasks.spawn(async move {
+                        let res = run_device(
+                            id,
+                            keychron_hid,
+                            dev,
+                            listening,
+                            tray_app.clone(),
+                            dev_cmd_rx,
+                            gone,
+                        )
+                        .await;
+                        update_handle.abort();
+                        dev_cmd_txs.lock().await.remove(&id);
+                        let res = res.map_err(|e| e.to_string());
+                        let mut tray_app_lock = tray_app.lock().await;
+                        if let Err(e) = &res {
+                            let name = tray_app_lock.device_name(id).to_string();
+                            tray_app_lock.notify_device_error(&name, e);
+                        }
+                        tray_app_lock.remove_device(id).await;
+                        res
+                    });
+                    task_devs.insert(task.id(), task_dev);
+                }
+                #[cfg(target_os = "linux")]
+                Err(KeychronError::PermissionDenied) => {
+                    let mut tray_app_lock = tray_app.lock().await;
+                    tray_app_lock.needs_udev_rules(true).await;
+                    // installing the rules retriggers the devices, the notification
+                    // waits for its action without holding the tray
+                    if !udev_rules_notified {
+                        udev_rules_notified = true;
+                        tokio::spawn(async {
+                            Tray::notify_udev_rules().await.ok();
+                        });
+                    }
+                }
+                Err(e) => {
+                    let name = TryInto::<KeychronDevice>::try_into(dev.product_id)
+                        .map_or("Keychron".to_string(), |kd| kd.to_string());
+                    let mut tray_app_lock = tray_app.lock().await;
+                    tray_app_lock.notify_device_error(&name, &e.to_string());
+                    failed.insert(dev, time::Instant::now());
+                    retry = true;
+                }
+            }
+        }
+        tokio::select! {
+            Some(joined) = device_tasks.join_next_with_id() => {
+                let (task_id, res) = match joined {
+                    Ok((task_id, res)) => (task_id, res),
+                    Err(e) => (e.id(), Err(e.to_string())),
+                };
+                if let Some(dev) = task_devs.remove(&task_id) {
+                    connected.remove(&dev);
+                    // the device is reconnected on the next round, after a pause if it failed
+                    if res.is_err() {
+                        failed.insert(dev, time::Instant::now());
+                    }
+                }
+            }
+            event = async {
+                match &hotplug {
+                    Some(h) => h.next().await,
+                    None => std::future::pending().await,
+                }
+            } => {
+                match event {
+                    // added devices are picked up on the next round
+                    Ok(HotplugEvent::Added) => (),
+                    Ok(HotplugEvent::Removed(path)) => {
+                        for (dev, gone) in &connected {
+                            if dev.path == path {
+                                gone.notify_one();
+                            }
                         }
-                    } else {
-                        return Err(e.into());
                     }
-                    #[cfg(not(target_os = "linux"))]
-                    return Err(e.into());
+                    Err(_) => hotplug = None,
+                }
+            }
+            _ = time::sleep(DEVICE_CHECK_PERIOD), if hotplug.is_none() || retry => {}
+        }
+    }
+}
+
+// Offers a firmware update for the device once it is identified, then once per period
+async fn check_firmware(
+    id: usize,
+    mut report_rx: watch::Receiver<Report>,
+    tray_app: Arc<Mutex<Tray>>,
+    update_checks: UpdateChecks,
+) {
+    loop {
+        // wait until the device is identified
+        let (vendor_product_id, current) = loop {
+            {
+                let r = report_rx.borrow_and_update();
+                if r.is_populated() {
+                    break (r.vendor_product_id(), r.fr_version_string());
                 }
             }
+            if report_rx.changed().await.is_err() {
+                return;
+            }
         };
-        #[cfg(target_os = "linux")]
+        // a reconnect reuses the last check until the period is over
+        let checked = update_checks.lock().await.get(&vendor_product_id).cloned();
+        let (release, wait) = match checked {
+            Some((at, release)) if at.elapsed() < UPDATE_CHECK_PERIOD => {
+                (release, UPDATE_CHECK_PERIOD - at.elapsed())
+            }
+            _ => {
+                let fetched =
+                    tokio::task::spawn_blocking(move || update::latest_firmware(vendor_product_id))
+                        .await;
+                // a failed check keeps the release found before
+                let release = match fetched {
+                    Ok(Ok(release)) => release,
+                    _ => checked.and_then(|(_, release)| release),
+                };
+                update_checks
+                    .lock()
+                    .await
+                    .insert(vendor_product_id, (time::Instant::now(), release.clone()));
+                (release, UPDATE_CHECK_PERIOD)
+            }
+        };
+        if let Some(release) = release
+            && update::is_newer(&release.version, &current)
         {
             let mut tray_app_lock = tray_app.lock().await;
-            tray_app_lock.needs_udev_rules(false).await;
+            tray_app_lock.firmware_available(id, release).await;
         }
-        let tray_app2 = tray_app.clone();
-        let report_handle: tokio::task::JoinHandle<Result<(), Box<dyn Error + Send + Sync>>> =
-            tokio::spawn(async move {
-                loop {
-                    report_rx.changed().await?;
-                    let dev = {
-                        let r = report_rx.borrow_and_update();
-                        if r.power.value == 255 {
-                            return Err("reset".into());
-                        }
-                        tray::Device {
-                            name: match r.keychron_device() {
-                                Ok(kd) => kd.to_string(),
-                                Err(_) => "".to_string(),
-                            },
-                            version: r.fr_version_string(),
-                            charging: r.power.state,
-                            battery: r.power.value,
-                            dpi: *r
-                                .dpi
-                                .levels_val
-                                .get(r.dpi.level[0] as usize)
-                                .unwrap_or(&0u16),
-                            polling_rate_level: r.polling_rate.level[0],
-                        }
-                    };
-                    let mut tray_app_lock = tray_app2.lock().await;
-                    tray_app_lock.update_device(dev).await;
-                }
-            });
+        time::sleep(wait).await;
+    }
+}
 
-        let poke_handle = tokio::task::spawn_blocking(move || keychron_hid.poke_device(&dev));
-        let res = tokio::try_join!(listen_handle, report_handle, poke_handle);
-        match res {
-            Ok((l, r, p)) => {
-                if let Some(e) = l.err() {
-                    if e.to_string()
-                        .to_lowercase()
-                        .find("input/output error")
-                        .is_some()
-                    {
-                        continue;
-                    } else {
-                        return Err(e.to_string().into());
-                    }
+// Keeps the tray in sync with one device until it goes away
+async fn run_device(
+    id: usize,
+    keychron_hid: Arc<KeychronHid>,
+    dev: DeviceInfo,
+    listening: (watch::Receiver<Report>, ListenHandle),
+    tray_app: Arc<Mutex<Tray>>,
+    mut cmd_rx: mpsc::Receiver<Command>,
+    gone: Arc<Notify>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let (mut report_rx, mut listen_handle) = listening;
+    // commands run apart from the reports, so these keep coming during a write
+    let tray_app4 = tray_app.clone();
+    let keychron_hid3 = keychron_hid.clone();
+    let dev3 = dev.clone();
+    let cmd_report_rx = report_rx.clone();
+    let cmd_handle = tokio::spawn(async move {
+        #[cfg(any(test, feature = "simulate"))]
+        let mut pairing: Option<tokio::task::JoinHandle<()>> = None;
+        while let Some(cmd) = cmd_rx.recv().await {
+            #[cfg(any(test, feature = "simulate"))]
+            if cmd == Command::Pair {
+                // pairing waits for the mouse, other commands go on meanwhile
+                if pairing.as_ref().is_some_and(|h| !h.is_finished()) {
+                    let tray_app_lock = tray_app4.lock().await;
+                    tray_app_lock.notify_command_error(id, "Already pairing");
+                    continue;
                 }
-                if let Some(e) = r.err() {
-                    if e.to_string().to_lowercase().find("reset").is_some() {
-                        continue;
-                    } else {
-                        return Err(e.to_string().into());
+                pairing = Some(tokio::spawn(pair_receiver(
+                    id,
+                    keychron_hid3.clone(),
+                    dev3.clone(),
+                    tray_app4.clone(),
+                )));
+                continue;
+            }
+            let report = *cmd_report_rx.borrow();
+            let keychron_hid = keychron_hid3.clone();
+            let dev = dev3.clone();
+            let res = match tokio::task::spawn_blocking(move || {
+                keychron_hid.send_command(&dev, &report, cmd)
+            })
+            .await
+            {
+                Ok(res) => res.map_err(|e| e.to_string()),
+                Err(e) => Err(e.to_string()),
+            };
+            if let Err(e) = res {
+                let tray_app_lock = tray_app4.lock().await;
+                tray_app_lock.notify_command_error(id, e.as_str());
+            }
+        }
+    });
+    let tray_app2 = tray_app.clone();
+    let keychron_hid2 = keychron_hid.clone();
+    let dev2 = dev.clone();
+    // pairing goes through the receiver, not the mouse reported behind it
+    #[cfg(any(test, feature = "simulate"))]
+    let pair = KeychronHid::is_receiver(&dev);
+    let mut report_handle: tokio::task::JoinHandle<Result<(), Box<dyn Error + Send + Sync>>> =
+        tokio::spawn(async move {
+            let mut last_profile = None;
+            loop {
+                report_rx.changed().await?;
+                let dev = {
+                    let r = report_rx.borrow_and_update();
+                    if r.power.value == 255 {
+                        return Err(KeychronError::DeviceAsleep.into());
                     }
+                    let dpi_level_num = (r.dpi.level_num as usize).min(r.dpi.levels_val.len());
+                    tray::Device {
+                        name: match r.keychron_device() {
+                            Ok(kd) => kd.to_string(),
+                            Err(_) => "".to_string(),
+                        },
+                        product_id: r.pid,
+                        version: r.fr_version_string(),
+                        charging: r.power.state,
+                        battery: r.power.value,
+                        dpi: r.current_dpi(),
+                        dpi_level: r.current_settings().map(|s| s.dpi_level),
+                        dpi_levels: r.dpi.levels_val[..dpi_level_num].to_vec(),
+                        polling_rate: r.current_polling_rate(),
+                        polling_rate_level: r.current_settings().map(|s| s.polling_rate_level),
+                        polling_rates: r.polling_rate.rates(),
+                        profile: r.profile.current,
+                        profile_num: if r.support.profile_support {
+                            r.profile.level_num
+                        } else {
+                            0
+                        },
+                        debounce: r.support.debounce_support.then_some(r.debounce),
+                        sleep: r.sleep_known().then_some(r.sleep),
+                        sleep_settable: r.can_set_sleep(),
+                        lod: r.sys_features_layout().and(r.sys_features.lod),
+                        features: r.features(),
+                        scroll: r.support.scroll_support.then_some(r.scroll),
+                        scroll_natural: r.sys_features_layout().map(|_| r.sys_features.scroll_dir),
+                        wake_sources: r.wake_sources(),
+                        #[cfg(any(test, feature = "simulate"))]
+                        pair,
+                        defaults: r
+                            .keychron_device()
+                            .is_ok_and(|kd| kd.descriptor().is_some()),
+                        receiver: r.receiver.map(|rc| {
+                            (
+                                match rc.keychron_device() {
+                                    Ok(kd) => kd.to_string(),
+                                    Err(_) => "".to_string(),
+                                },
+                                rc.fr_version_string(),
+                            )
+                        }),
+                    }
+                };
+                if last_profile.is_some_and(|p| p != dev.profile) {
+                    // the settings of the new profile need to be fetched, a
+                    // failure leaves the old ones shown until the next report
+                    let keychron_hid = keychron_hid2.clone();
+                    let dev = dev2.clone();
+                    let _ =
+                        tokio::task::spawn_blocking(move || keychron_hid.poke_device(&dev)).await;
                 }
-                if let Some(e) = p.err() {
-                    return Err(e.into());
-                }
-            }
-            Err(e) => {
-                return Err(e.into());
+                last_profile = Some(dev.profile);
+                let mut tray_app_lock = tray_app2.lock().await;
+                tray_app_lock.update_device(id, dev).await;
             }
+        });
+
+    let poke_handle = tokio::task::spawn_blocking(move || keychron_hid.poke_device(&dev));
+    let res = tokio::select! {
+        res = async { tokio::try_join!(&mut listen_handle, &mut report_handle, poke_handle) } => res,
+        _ = gone.notified() => {
+            // no need to wait for the reads to fail, but for the device to be closed
+            report_handle.abort();
+            cmd_handle.abort();
+            listen_handle.cancel().await;
+            return Ok(());
         }
-        break;
+    };
+    cmd_handle.abort();
+    let (l, r, p) = res?;
+    // the reports stop once the device is gone, so that error comes first
+    match l {
+        Err(e) if e.is_reconnectable() => return Ok(()),
+        Err(e) => return Err(e.into()),
+        Ok(()) => (),
+    }
+    if let Err(e) = r {
+        return match e.downcast_ref::<KeychronError>() {
+            Some(ke) if ke.is_reconnectable() => Ok(()),
+            _ => Err(e),
+        };
     }
+    p?;
     Ok(())
 }
+
+// Pairs a mouse to the receiver, showing the time left in the tray
+#[cfg(any(test, feature = "simulate"))]
+async fn pair_receiver(
+    id: usize,
+    keychron_hid: Arc<KeychronHid>,
+    dev: DeviceInfo,
+    tray_app: Arc<Mutex<Tray>>,
+) {
+    {
+        let tray_app_lock = tray_app.lock().await;
+        tray_app_lock.notify_pairing();
+    }
+    let (progress_tx, mut progress_rx) = mpsc::unbounded_channel();
+    let keychron_hid2 = keychron_hid.clone();
+    let dev2 = dev.clone();
+    let pair = tokio::task::spawn_blocking(move || {
+        keychron_hid2.pair(&dev2, |left| {
+            progress_tx.send(left).ok();
+        })
+    });
+    // the progress ends with the pairing
+    while let Some(left) = progress_rx.recv().await {
+        let mut tray_app_lock = tray_app.lock().await;
+        tray_app_lock.pairing_progress(id, Some(left)).await;
+    }
+    let res = match pair.await {
+        Ok(res) => res.map_err(|e| e.to_string()),
+        Err(e) => Err(e.to_string()),
+    };
+    if res.is_ok() {
+        // the newly paired mouse answers for the receiver from now on
+        tokio::task::spawn_blocking(move || keychron_hid.poke_device(&dev))
+            .await
+            .ok();
+    }
+    let mut tray_app_lock = tray_app.lock().await;
+    tray_app_lock.pairing_progress(id, None).await;
+    tray_app_lock.notify_paired(res);
+}
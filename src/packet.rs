@@ -0,0 +1,415 @@
+use crate::{
+    button::ButtonAction,
+    error::KeychronError,
+    report::{
+        PROFILE_NUM, PairState, REPORT_TYPE_BASE, REPORT_TYPE_BUTTON, REPORT_TYPE_DESCRIPTION,
+        REPORT_TYPE_FULL, REPORT_TYPE_FULL_SHORT, REPORT_TYPE_LIGHT, REPORT_TYPE_PAIR,
+        REPORT_TYPE_PROFILE, REPORT_TYPE_SETTINGS, REPORT_TYPE_SETTINGS_RECEIVER,
+        REPORT_TYPE_STATUS, REPORT_TYPE_STATUS_RECEIVER, ReportDPI, ReportDebounce, ReportLight,
+        ReportPollingRate, ReportPower, ReportProfile, ReportProfileSettings, ReportRouseOrigin,
+        ReportScroll, ReportSleep, ReportSupport, ReportSysFeatures,
+    },
+};
+
+// A report as sent by the device, without the report id
+#[derive(Debug, Clone, PartialEq, Eq, Copy)]
+pub enum Packet {
+    Description(DescriptionPacket),
+    Full(FullPacket),
+    FullShort(FullShortPacket),
+    Base(BasePacket),
+    // Only some status reports carry the power state
+    Status(Option<StatusPacket>),
+    // Only some settings reports carry the settings
+    Settings(Option<SettingsPacket>),
+    Light(ReportLight),
+    Button(ButtonPacket),
+    Profile(ProfilePacket),
+    Pair(PairState),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default, Copy)]
+pub struct DescriptionPacket {
+    pub version: u16,
+    pub vid: u16,
+    pub pid: u16,
+    pub fr_version: [u8; 3],
+    pub work_mode: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default, Copy)]
+pub struct FullPacket {
+    pub profile: ReportProfile,
+    pub profiles: [ReportProfileSettings; PROFILE_NUM],
+    pub dpi: ReportDPI,
+    pub polling_rate: ReportPollingRate,
+    pub sys_features: ReportSysFeatures,
+    pub debounce: ReportDebounce,
+    pub scroll: ReportScroll,
+    pub sleep: ReportSleep,
+    pub power: ReportPower,
+    pub rouse_origin: ReportRouseOrigin,
+    pub support: ReportSupport,
+}
+
+// Full report of devices without onboard profiles nor polling rate levels
+#[derive(Debug, Clone, PartialEq, Eq, Default, Copy)]
+pub struct FullShortPacket {
+    pub profile: u8,
+    pub profiles: [ReportProfileSettings; PROFILE_NUM],
+    pub dpi_levels_val: [u16; 5],
+    pub dpi_level_num: u8,
+    // Without the 20K FPS flag
+    pub sys_features: ReportSysFeatures,
+    pub debounce: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default, Copy)]
+pub struct BasePacket {
+    pub work_mode: u8,
+    pub connect: u8,
+    pub power: ReportPower,
+    pub dpi_level: u8,
+    pub polling_rate_level: u8,
+    pub dpi_level_num: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default, Copy)]
+pub struct StatusPacket {
+    pub power: ReportPower,
+    pub dpi_level: u8,
+    pub polling_rate_level: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default, Copy)]
+pub struct SettingsPacket {
+    pub dpi_level: u8,
+    pub dpi_levels_val: [u16; 5],
+    pub dpi_level_num: u8,
+    // Without the 20K FPS flag
+    pub sys_features: ReportSysFeatures,
+    pub debounce: u8,
+    // In minutes
+    pub sleep_time: u16,
+    pub polling_rate_level: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default, Copy)]
+pub struct ButtonPacket {
+    pub button: u8,
+    pub action: Option<ButtonAction>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default, Copy)]
+pub struct ProfilePacket {
+    pub current: u8,
+}
+
+// Decode a report, starting with its type, any input gives a packet or an error
+pub fn decode(value: &[u8]) -> Result<Packet, KeychronError> {
+    let Some(&report_type) = value.first() else {
+        return Err(KeychronError::EmptyReport);
+    };
+    let min_len = match report_type {
+        REPORT_TYPE_DESCRIPTION => 10,
+        REPORT_TYPE_FULL => 54,
+        REPORT_TYPE_FULL_SHORT => 18,
+        REPORT_TYPE_BASE | REPORT_TYPE_LIGHT => 8,
+        REPORT_TYPE_STATUS | REPORT_TYPE_STATUS_RECEIVER => 10,
+        REPORT_TYPE_SETTINGS | REPORT_TYPE_SETTINGS_RECEIVER => 55,
+        REPORT_TYPE_BUTTON => 6,
+        REPORT_TYPE_PROFILE | REPORT_TYPE_PAIR => 2,
+        _ => return Err(KeychronError::UnknownReport { report_type }),
+    };
+    if value.len() < min_len {
+        return Err(KeychronError::ProtocolError { report_type });
+    }
+    let u16_at = |i: usize| ((value[i + 1] as u16) << 8) | (value[i] as u16);
+    Ok(match report_type {
+        REPORT_TYPE_DESCRIPTION => Packet::Description(DescriptionPacket {
+            version: u16_at(1),
+            vid: u16_at(3),
+            pid: u16_at(5),
+            fr_version: [value[8], (value[7] >> 4) & 15, value[7] & 15],
+            work_mode: value[9] & 7,
+        }),
+        REPORT_TYPE_FULL => Packet::Full(FullPacket {
+            profile: ReportProfile {
+                current: value[1],
+                level_num: value[50],
+            },
+            profiles: decode_profiles(&value[2..5]),
+            dpi: ReportDPI {
+                levels_val: [u16_at(5), u16_at(7), u16_at(9), u16_at(11), u16_at(13)],
+                level_num: value[16],
+                level_val_max: u16_at(40),
+                level_val_step: if value[42] != 0 { value[42] } else { 50 },
+            },
+            polling_rate: ReportPollingRate {
+                levels_val: value[43..49].try_into().unwrap_or_default(),
+                level_num: if value[49] != 0 { value[49] } else { 6 },
+            },
+            sys_features: ReportSysFeatures {
+                lod: (value[15] & 3).try_into().ok(),
+                wave: ((value[15] >> 2) & 1) != 0,
+                line: ((value[15] >> 3) & 1) != 0,
+                motion: ((value[15] >> 4) & 1) != 0,
+                scroll_dir: ((value[15] >> 6) & 1) != 0,
+                fps20k: (value[52] & 1) != 0,
+                flags_raw: value[15],
+                fps20k_raw: value[52],
+            },
+            debounce: ReportDebounce {
+                value: value[17],
+                values: value[30..40].try_into().unwrap_or_default(),
+            },
+            scroll: ReportScroll {
+                speed: value[27],
+                inertia: value[28],
+                spl: value[29],
+            },
+            sleep: ReportSleep {
+                time: value[18] as u16,
+            },
+            power: ReportPower {
+                value: value[19] & 127,
+                state: ((value[19] >> 7) & 1) != 0,
+            },
+            rouse_origin: ReportRouseOrigin {
+                key: ((value[51] >> 4) & 1) != 0,
+                key_support: (value[51] & 1) != 0,
+                scroll: ((value[51] >> 5) & 1) != 0,
+                scroll_support: (value[51] & 2) != 0,
+                mmove: ((value[51] >> 6) & 1) != 0,
+                move_support: (value[51] & 4) != 0,
+                side_scroll: ((value[51] >> 7) & 1) != 0,
+                side_scroll_support: (value[51] & 8) != 0,
+                flags_raw: value[51],
+            },
+            support: ReportSupport {
+                scroll_support: (value[26] & 1) != 0,
+                debounce_support: (value[26] & 2) != 0,
+                max_and_step_support: (value[26] & 8) != 0,
+                polling_gears_support: (value[26] & 16) != 0,
+                profile_support: (value[26] & 32) != 0,
+                rouse_origin_support: (value[26] & 64) != 0,
+                fps20k_support: (value[26] & 128) != 0,
+                sleep_support: false,      // why ?
+                loop_dpress_support: true, // why ?
+                pair_key_support: ((value[53] >> 1) & 1) != 0,
+            },
+        }),
+        REPORT_TYPE_FULL_SHORT => Packet::FullShort(FullShortPacket {
+            profile: value[1],
+            profiles: decode_profiles(&value[2..5]),
+            dpi_levels_val: [u16_at(5), u16_at(7), u16_at(9), u16_at(11), u16_at(13)],
+            dpi_level_num: value[16],
+            sys_features: ReportSysFeatures {
+                lod: (value[15] & 3).try_into().ok(),
+                wave: (value[15] & 4) != 0,
+                line: (value[15] & 8) != 0,
+                motion: (value[15] & 16) != 0,
+                scroll_dir: (value[15] & 64) != 0,
+                fps20k: false,
+                flags_raw: value[15],
+                fps20k_raw: 0,
+            },
+            debounce: value[17],
+        }),
+        REPORT_TYPE_LIGHT => Packet::Light(ReportLight {
+            mode: value[1],
+            speed: value[3],
+            brightness: value[4],
+            rgb: [value[5], value[6], value[7]],
+        }),
+        REPORT_TYPE_BASE => Packet::Base(BasePacket {
+            work_mode: value[1],
+            connect: value[2],
+            power: ReportPower {
+                value: value[4],
+                state: value[3] != 0,
+            },
+            dpi_level: value[5],
+            polling_rate_level: value[6],
+            dpi_level_num: value[7],
+        }),
+        REPORT_TYPE_STATUS | REPORT_TYPE_STATUS_RECEIVER => Packet::Status(
+            ((value[2] == 140 || value[2] == 142) && value[3] == 1).then(|| StatusPacket {
+                power: ReportPower {
+                    value: value[6],
+                    state: value[5] != 0,
+                },
+                dpi_level: value[8],
+                polling_rate_level: value[9].saturating_sub(1),
+            }),
+        ),
+        REPORT_TYPE_SETTINGS | REPORT_TYPE_SETTINGS_RECEIVER => {
+            Packet::Settings((value[3] == 1).then(|| SettingsPacket {
+                dpi_level: value[7],
+                dpi_levels_val: [u16_at(8), u16_at(12), u16_at(16), u16_at(20), u16_at(24)],
+                dpi_level_num: value[6].count_ones() as u8,
+                sys_features: ReportSysFeatures {
+                    lod: (value[43] & 3).try_into().ok(),
+                    wave: ((value[4] >> 4) & 1) != 0,
+                    line: (value[4] & 1) != 0,
+                    motion: ((value[4] >> 5) & 1) != 0,
+                    scroll_dir: ((value[4] >> 7) & 1) != 0,
+                    fps20k: false,
+                    // not laid out as in the full layouts
+                    flags_raw: 0,
+                    fps20k_raw: 0,
+                },
+                debounce: value[54],
+                sleep_time: u16_at(52),
+                polling_rate_level: value[5].saturating_sub(1),
+            }))
+        }
+        REPORT_TYPE_BUTTON => Packet::Button(ButtonPacket {
+            button: value[1],
+            action: ButtonAction::from_bytes(&[value[2], value[3], value[4], value[5]]),
+        }),
+        REPORT_TYPE_PROFILE => Packet::Profile(ProfilePacket { current: value[1] }),
+        REPORT_TYPE_PAIR => Packet::Pair(
+            value[1]
+                .try_into()
+                .map_err(|_| KeychronError::ProtocolError { report_type })?,
+        ),
+        _ => return Err(KeychronError::UnknownReport { report_type }),
+    })
+}
+
+// One byte per profile, the low nibble is the DPI level and the high nibble the polling rate level
+fn decode_profiles(value: &[u8]) -> [ReportProfileSettings; PROFILE_NUM] {
+    let mut profiles = [ReportProfileSettings::default(); PROFILE_NUM];
+    for (p, v) in profiles.iter_mut().zip(value) {
+        p.dpi_level = v & 15;
+        p.polling_rate_level = (v >> 4) & 15;
+    }
+    profiles
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::{Lod, Report};
+
+    #[test]
+    fn decode_description() {
+        let packet = decode(&[
+            REPORT_TYPE_DESCRIPTION,
+            1,
+            1,
+            0x34,
+            0x34,
+            0x49,
+            0xd0,
+            0x01,
+            1,
+            1,
+        ]);
+        assert_eq!(
+            packet.unwrap(),
+            Packet::Description(DescriptionPacket {
+                version: 0x0101,
+                vid: 0x3434,
+                pid: 0xd049,
+                fr_version: [1, 0, 1],
+                work_mode: 1,
+            })
+        );
+    }
+
+    #[test]
+    fn decode_profile_levels() {
+        let profiles = decode_profiles(&[0x21, 0x5f, 0x03]);
+        assert_eq!(profiles[0].dpi_level, 1);
+        assert_eq!(profiles[0].polling_rate_level, 2);
+        assert_eq!(profiles[1].dpi_level, 15);
+        assert_eq!(profiles[1].polling_rate_level, 5);
+        assert_eq!(profiles[2].dpi_level, 3);
+        assert_eq!(profiles[2].polling_rate_level, 0);
+        // missing profiles keep the defaults
+        assert_eq!(
+            decode_profiles(&[0x21])[1],
+            ReportProfileSettings::default()
+        );
+    }
+
+    #[test]
+    fn decode_full() {
+        let mut value = [0u8; 54];
+        value[0] = REPORT_TYPE_FULL;
+        value[1] = 1;
+        value[2..5].copy_from_slice(&[0x21, 0x32, 0x10]);
+        value[5..7].copy_from_slice(&800u16.to_le_bytes());
+        value[15] = 0x51;
+        value[16] = 1;
+        value[19] = 0x80 | 42;
+        let Packet::Full(full) = decode(&value).unwrap() else {
+            panic!("not a full report");
+        };
+        assert_eq!(full.profile.current, 1);
+        assert_eq!(full.profiles[1].dpi_level, 2);
+        assert_eq!(full.profiles[1].polling_rate_level, 3);
+        assert_eq!(full.dpi.levels_val[0], 800);
+        // defaults when not reported
+        assert_eq!(full.dpi.level_val_step, 50);
+        assert_eq!(full.polling_rate.level_num, 6);
+        assert_eq!(full.sys_features.lod, Some(Lod::Mm1_0));
+        assert!(full.sys_features.motion && full.sys_features.scroll_dir);
+        assert_eq!(
+            full.power,
+            ReportPower {
+                value: 42,
+                state: true
+            }
+        );
+    }
+
+    #[test]
+    fn decode_short_reports() {
+        assert!(matches!(
+            decode(&[REPORT_TYPE_FULL, 0, 0]),
+            Err(KeychronError::ProtocolError {
+                report_type: REPORT_TYPE_FULL
+            })
+        ));
+        assert_eq!(
+            decode(&[REPORT_TYPE_PROFILE, 2]).unwrap(),
+            Packet::Profile(ProfilePacket { current: 2 })
+        );
+        assert!(matches!(
+            decode(&[99]),
+            Err(KeychronError::UnknownReport { report_type: 99 })
+        ));
+        assert!(matches!(decode(&[]), Err(KeychronError::EmptyReport)));
+    }
+
+    #[test]
+    fn report_applies_packets() {
+        let mut r = Report::default();
+        r.apply(Packet::Profile(ProfilePacket { current: 1 }));
+        r.apply(Packet::Status(Some(StatusPacket {
+            power: ReportPower {
+                value: 80,
+                state: false,
+            },
+            dpi_level: 3,
+            polling_rate_level: 2,
+        })));
+        assert_eq!(r.profile.current, 1);
+        assert_eq!(r.power.value, 80);
+        assert_eq!(r.profiles[1].dpi_level, 3);
+        assert_eq!(r.profiles[0].dpi_level, 0);
+        // levels of an unknown profile are not pinned on another one
+        r.apply(Packet::Profile(ProfilePacket { current: 7 }));
+        r.apply(Packet::Status(Some(StatusPacket {
+            power: ReportPower::default(),
+            dpi_level: 4,
+            polling_rate_level: 1,
+        })));
+        assert_eq!(r.profile_index(), None);
+        assert_eq!(r.current_dpi(), 0);
+        assert!(r.profiles.iter().all(|p| p.dpi_level != 4));
+    }
+}
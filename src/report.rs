@@ -1,16 +1,37 @@
-use crate::keychron_device::KeychronDevice;
-use num_enum::TryFromPrimitiveError;
+use std::{error, fmt, str};
 
-pub const REPORT_TYPE_UNKNOWN_1: u8 = 1;
+use crate::button::{BUTTON_NUM, ButtonAction};
+use crate::error::KeychronError;
+use crate::keychron_device::{KeychronDevice, KeychronDeviceCategory};
+use crate::packet::{self, Packet};
+use num_enum::{IntoPrimitive, TryFromPrimitive, TryFromPrimitiveError};
+
+pub const REPORT_TYPE_STATUS: u8 = 1;
 pub const REPORT_TYPE_DESCRIPTION: u8 = 2;
-pub const REPORT_TYPE_UNKNOWN_4: u8 = 4;
+pub const REPORT_TYPE_SETTINGS: u8 = 4;
 pub const REPORT_TYPE_FULL: u8 = 6;
-pub const REPORT_TYPE_UNKNOWN_7: u8 = 7;
-pub const REPORT_TYPE_UNKNOWN_65: u8 = 65;
-pub const REPORT_TYPE_UNKNOWN_68: u8 = 68;
+// Full report of devices without onboard profiles
+pub const REPORT_TYPE_FULL_SHORT: u8 = 7;
+// Status and settings with the 0x40 bit set, as relayed by a receiver
+pub const REPORT_TYPE_STATUS_RECEIVER: u8 = 65;
+pub const REPORT_TYPE_SETTINGS_RECEIVER: u8 = 68;
 pub const REPORT_TYPE_LIGHT: u8 = 225;
 pub const REPORT_TYPE_BASE: u8 = 226;
+// Button, macro and pair are assumed to fill the gaps around the known types, none
+// of them was seen in a capture. Their layouts are assumed too, as is writing them
+// with `keychron_hid::CMD_SET`.
+pub const REPORT_TYPE_BUTTON: u8 = 227;
+pub const REPORT_TYPE_MACRO: u8 = 228;
 pub const REPORT_TYPE_PROFILE: u8 = 229;
+pub const REPORT_TYPE_PAIR: u8 = 230;
+
+// lowest DPI of the "dpi.limit" of the launcher descriptors
+pub const DPI_LEVEL_VAL_MIN: u16 = 50;
+pub const PROFILE_NUM: usize = 3;
+pub const SCROLL_SPEED_MAX: u8 = 10;
+pub const SCROLL_INERTIA_MAX: u8 = 10;
+pub const SCROLL_LINES_MAX: u8 = 10;
+pub const SLEEP_TIME_PRESETS: [u16; 6] = [1, 2, 5, 10, 30, 60];
 
 pub trait TryMerge<T> {
     type Error;
@@ -23,30 +44,291 @@ pub struct ReportProfile {
     pub level_num: u8,
 }
 
+// Settings stored separately by each onboard profile
+#[derive(Debug, Clone, PartialEq, Eq, Default, Copy)]
+pub struct ReportProfileSettings {
+    pub dpi_level: u8,
+    pub polling_rate_level: u8,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Default, Copy)]
 pub struct ReportDPI {
-    pub level: [u8; 3],
     pub levels_val: [u16; 5],
     pub level_num: u8,
     pub level_val_max: u16,
     pub level_val_step: u8,
 }
 
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Hash)]
+pub enum InvalidDpiError {
+    Unsupported,
+    LevelNum(usize),
+    TooLow(u16),
+    TooHigh(u16),
+    Step(u16),
+}
+
+impl fmt::Display for InvalidDpiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            InvalidDpiError::Unsupported => {
+                f.write_str("Device does not support editing DPI levels")
+            }
+            InvalidDpiError::LevelNum(n) => write!(f, "Invalid number of DPI levels given: {}", n),
+            InvalidDpiError::TooLow(v) => write!(f, "DPI value {} is below the device minimum", v),
+            InvalidDpiError::TooHigh(v) => write!(f, "DPI value {} is above the device maximum", v),
+            InvalidDpiError::Step(v) => {
+                write!(f, "DPI value {} is not a multiple of the device step", v)
+            }
+        }
+    }
+}
+
+impl error::Error for InvalidDpiError {
+    fn description(&self) -> &str {
+        "invalid DPI levels"
+    }
+}
+
+impl ReportDPI {
+    // Checks the DPI values against the device limits
+    pub fn validate_levels(&self, levels: &[u16]) -> Result<(), InvalidDpiError> {
+        if levels.is_empty() || levels.len() > self.levels_val.len() {
+            return Err(InvalidDpiError::LevelNum(levels.len()));
+        }
+        for &v in levels {
+            if v < DPI_LEVEL_VAL_MIN {
+                return Err(InvalidDpiError::TooLow(v));
+            }
+            if self.level_val_max != 0 && v > self.level_val_max {
+                return Err(InvalidDpiError::TooHigh(v));
+            }
+            if self.level_val_step != 0 && v % self.level_val_step as u16 != 0 {
+                return Err(InvalidDpiError::Step(v));
+            }
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Default, Copy)]
 pub struct ReportPollingRate {
-    pub level: [u8; 3],
     pub levels_val: [u8; 6],
     pub level_num: u8,
 }
 
+impl ReportPollingRate {
+    // Polling rate selected by the given level
+    pub fn rate(&self, level: u8) -> Option<PollingRate> {
+        if level >= self.level_num {
+            return None;
+        }
+        if self.levels_val.iter().all(|&v| v == 0) {
+            // no mapping reported, levels are the rates in ascending order
+            return level.try_into().ok();
+        }
+        self.levels_val
+            .get(level as usize)
+            .and_then(|&v| v.try_into().ok())
+    }
+
+    // Polling rates of all the levels
+    pub fn rates(&self) -> Vec<PollingRate> {
+        (0..self.level_num.min(self.levels_val.len() as u8))
+            .map_while(|l| self.rate(l))
+            .collect()
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Hash, IntoPrimitive, TryFromPrimitive)]
+#[repr(u8)]
+pub enum PollingRate {
+    Hz125,
+    Hz500,
+    Hz1000,
+    Hz2000,
+    Hz4000,
+    Hz8000,
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Hash)]
+pub struct ParsePollingRateError;
+
+impl fmt::Display for ParsePollingRateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Invalid polling rate given")
+    }
+}
+
+impl error::Error for ParsePollingRateError {
+    fn description(&self) -> &str {
+        "invalid polling rate"
+    }
+}
+
+impl fmt::Display for PollingRate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} Hz", self.hz())
+    }
+}
+
+impl str::FromStr for PollingRate {
+    type Err = ParsePollingRateError;
+
+    fn from_str(s: &str) -> ::std::result::Result<Self, Self::Err> {
+        match s.replace(" ", "").to_uppercase().trim_end_matches("HZ") {
+            "125" => Ok(PollingRate::Hz125),
+            "500" => Ok(PollingRate::Hz500),
+            "1000" => Ok(PollingRate::Hz1000),
+            "2000" => Ok(PollingRate::Hz2000),
+            "4000" => Ok(PollingRate::Hz4000),
+            "8000" => Ok(PollingRate::Hz8000),
+            _ => Err(ParsePollingRateError),
+        }
+    }
+}
+
+impl PollingRate {
+    pub fn hz(&self) -> u16 {
+        match *self {
+            PollingRate::Hz125 => 125,
+            PollingRate::Hz500 => 500,
+            PollingRate::Hz1000 => 1000,
+            PollingRate::Hz2000 => 2000,
+            PollingRate::Hz4000 => 4000,
+            PollingRate::Hz8000 => 8000,
+        }
+    }
+}
+
+// Lift-off distance, the discriminant is the index used by the device
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Hash, IntoPrimitive, TryFromPrimitive)]
+#[repr(u8)]
+pub enum Lod {
+    Mm1_0 = 1,
+    Mm2_0 = 2,
+    Mm0_7 = 3,
+}
+
+pub const LOD_OPTIONS: [Lod; 3] = [Lod::Mm0_7, Lod::Mm1_0, Lod::Mm2_0];
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Hash)]
+pub struct ParseLodError;
+
+impl fmt::Display for ParseLodError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Invalid lift-off distance given")
+    }
+}
+
+impl error::Error for ParseLodError {
+    fn description(&self) -> &str {
+        "invalid lift-off distance"
+    }
+}
+
+impl fmt::Display for Lod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Lod::Mm0_7 => f.write_str("0.7mm"),
+            Lod::Mm1_0 => f.write_str("1.0mm"),
+            Lod::Mm2_0 => f.write_str("2.0mm"),
+        }
+    }
+}
+
+impl str::FromStr for Lod {
+    type Err = ParseLodError;
+
+    fn from_str(s: &str) -> ::std::result::Result<Self, Self::Err> {
+        match s.replace(" ", "").to_uppercase().trim_end_matches("MM") {
+            "0.7" => Ok(Lod::Mm0_7),
+            "1" | "1.0" => Ok(Lod::Mm1_0),
+            "2" | "2.0" => Ok(Lod::Mm2_0),
+            _ => Err(ParseLodError),
+        }
+    }
+}
+
+// Sensor features that can be toggled
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Hash)]
+pub enum Feature {
+    AngleSnapping,
+    RippleControl,
+    MotionSync,
+    Fps20k,
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Hash)]
+pub struct ParseFeatureError;
+
+impl fmt::Display for ParseFeatureError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Invalid feature given")
+    }
+}
+
+impl error::Error for ParseFeatureError {
+    fn description(&self) -> &str {
+        "invalid feature"
+    }
+}
+
+impl fmt::Display for Feature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Feature::AngleSnapping => f.write_str("Angle snapping"),
+            Feature::RippleControl => f.write_str("Ripple control"),
+            Feature::MotionSync => f.write_str("Motion sync"),
+            Feature::Fps20k => f.write_str("20K FPS"),
+        }
+    }
+}
+
+impl str::FromStr for Feature {
+    type Err = ParseFeatureError;
+
+    fn from_str(s: &str) -> ::std::result::Result<Self, Self::Err> {
+        match s.replace([' ', '-', '_'], "").to_uppercase().as_str() {
+            "ANGLESNAPPING" => Ok(Feature::AngleSnapping),
+            "RIPPLECONTROL" => Ok(Feature::RippleControl),
+            "MOTIONSYNC" => Ok(Feature::MotionSync),
+            "20KFPS" | "FPS20K" => Ok(Feature::Fps20k),
+            _ => Err(ParseFeatureError),
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Default, Copy)]
 pub struct ReportSysFeatures {
-    pub lod: bool,
+    pub lod: Option<Lod>,
     pub wave: bool,
     pub line: bool,
     pub motion: bool,
     pub scroll_dir: bool,
     pub fps20k: bool,
+    // Byte 15 of the full layouts as reported
+    pub flags_raw: u8,
+    // Byte 52 of the full layout as reported
+    pub fps20k_raw: u8,
+}
+
+impl ReportSysFeatures {
+    // Feature flags as laid out in the full report, the bits not known here are
+    // kept as reported
+    pub fn flags(&self) -> u8 {
+        self.flags_raw & 0b1010_0000
+            | self.lod.map_or(self.flags_raw & 3, u8::from)
+            | (self.wave as u8) << 2
+            | (self.line as u8) << 3
+            | (self.motion as u8) << 4
+            | (self.scroll_dir as u8) << 6
+    }
+
+    // Byte 52 of the full layout with the 20K FPS bit set as wanted
+    pub fn fps20k_flags(&self) -> u8 {
+        self.fps20k_raw & !1 | self.fps20k as u8
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Default, Copy)]
@@ -55,6 +337,19 @@ pub struct ReportDebounce {
     pub values: [u8; 10],
 }
 
+impl ReportDebounce {
+    // Debounce times in ms the device allows, without the unused trailing entries
+    pub fn options(&self) -> Vec<u8> {
+        let len = self
+            .values
+            .iter()
+            .rposition(|&v| v != 0)
+            .map_or(0, |i| i + 1);
+        self.values[..len].to_vec()
+    }
+}
+
+// Scroll wheel speed from 1, inertia from 0 (off) and lines scrolled per notch from 1
 #[derive(Debug, Clone, PartialEq, Eq, Default, Copy)]
 pub struct ReportScroll {
     pub speed: u8,
@@ -62,17 +357,84 @@ pub struct ReportScroll {
     pub spl: u8,
 }
 
+// Idle time before the device goes to sleep. No launcher data tells the unit of
+// the raw value, it is taken as minutes in both layouts.
 #[derive(Debug, Clone, PartialEq, Eq, Default, Copy)]
 pub struct ReportSleep {
     pub time: u16,
 }
 
+impl fmt::Display for ReportSleep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (h, m) = (self.time / 60, self.time % 60);
+        let parts: Vec<String> = [(h, "h"), (m, "min")]
+            .iter()
+            .filter(|(v, _)| *v != 0)
+            .map(|(v, u)| format!("{} {}", v, u))
+            .collect();
+        if parts.is_empty() {
+            f.write_str("never")
+        } else {
+            f.write_str(parts.join(" ").as_str())
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Default, Copy)]
 pub struct ReportPower {
     pub value: u8,
     pub state: bool,
 }
 
+// Inputs that can wake the device up
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Hash)]
+pub enum WakeSource {
+    Key,
+    Scroll,
+    Move,
+    SideScroll,
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Hash)]
+pub struct ParseWakeSourceError;
+
+impl fmt::Display for ParseWakeSourceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Invalid wake source given")
+    }
+}
+
+impl error::Error for ParseWakeSourceError {
+    fn description(&self) -> &str {
+        "invalid wake source"
+    }
+}
+
+impl fmt::Display for WakeSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            WakeSource::Key => f.write_str("Button press"),
+            WakeSource::Scroll => f.write_str("Scrolling"),
+            WakeSource::Move => f.write_str("Movement"),
+            WakeSource::SideScroll => f.write_str("Side scrolling"),
+        }
+    }
+}
+
+impl str::FromStr for WakeSource {
+    type Err = ParseWakeSourceError;
+
+    fn from_str(s: &str) -> ::std::result::Result<Self, Self::Err> {
+        match s.replace([' ', '-', '_'], "").to_uppercase().as_str() {
+            "KEY" | "BUTTON" => Ok(WakeSource::Key),
+            "SCROLL" => Ok(WakeSource::Scroll),
+            "MOVE" | "MOVEMENT" => Ok(WakeSource::Move),
+            "SIDESCROLL" => Ok(WakeSource::SideScroll),
+            _ => Err(ParseWakeSourceError),
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Default, Copy)]
 pub struct ReportRouseOrigin {
     pub key: bool,
@@ -83,6 +445,20 @@ pub struct ReportRouseOrigin {
     pub move_support: bool,
     pub side_scroll: bool,
     pub side_scroll_support: bool,
+    // Byte 51 of the full layout as reported
+    pub flags_raw: u8,
+}
+
+impl ReportRouseOrigin {
+    // Support bits in the low nibble, kept as reported since they are read-only,
+    // and enabled sources in the high nibble
+    pub fn flags(&self) -> u8 {
+        self.flags_raw & 15
+            | (self.key as u8) << 4
+            | (self.scroll as u8) << 5
+            | (self.mmove as u8) << 6
+            | (self.side_scroll as u8) << 7
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Default, Copy)]
@@ -107,6 +483,36 @@ pub struct ReportLight {
     pub rgb: [u8; 3],
 }
 
+// Pairing progress reported by a receiver
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Hash, IntoPrimitive, TryFromPrimitive)]
+#[repr(u8)]
+pub enum PairState {
+    Idle = 0,
+    Pairing = 1,
+    Paired = 2,
+    Failed = 3,
+}
+
+// The receiver a wireless mouse is reached through
+#[derive(Debug, Clone, PartialEq, Eq, Default, Copy)]
+pub struct ReportReceiver {
+    pub pid: u16,
+    pub fr_version: [u8; 3],
+}
+
+impl ReportReceiver {
+    pub fn fr_version_string(&self) -> String {
+        format!(
+            "{}.{}.{}",
+            self.fr_version[0], self.fr_version[1], self.fr_version[2]
+        )
+    }
+
+    pub fn keychron_device(&self) -> Result<KeychronDevice, TryFromPrimitiveError<KeychronDevice>> {
+        self.pid.try_into()
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Default, Copy)]
 pub struct Report {
     pub vid: u16,
@@ -116,6 +522,7 @@ pub struct Report {
     pub work_mode: u8,
     pub connect: u8,
     pub profile: ReportProfile,
+    pub profiles: [ReportProfileSettings; PROFILE_NUM],
     pub dpi: ReportDPI,
     pub polling_rate: ReportPollingRate,
     pub sys_features: ReportSysFeatures,
@@ -126,6 +533,12 @@ pub struct Report {
     pub rouse_origin: ReportRouseOrigin,
     pub support: ReportSupport,
     pub light: ReportLight,
+    // Action of each button index, once requested
+    pub buttons: [Option<ButtonAction>; BUTTON_NUM],
+    // Set when the mouse is connected through a receiver
+    pub receiver: Option<ReportReceiver>,
+    // Report type of the layout the settings last came in
+    pub layout: Option<u8>,
 }
 
 impl Report {
@@ -136,7 +549,6 @@ impl Report {
         )
     }
 
-    #[allow(dead_code)]
     pub fn vendor_product_id(&self) -> u32 {
         65536u32 * self.vid as u32 + self.pid as u32
     }
@@ -144,179 +556,479 @@ impl Report {
     pub fn keychron_device(&self) -> Result<KeychronDevice, TryFromPrimitiveError<KeychronDevice>> {
         self.pid.try_into()
     }
-}
 
-impl TryMerge<&[u8]> for Report {
-    type Error = &'static str;
-    fn merge(&mut self, value: &[u8]) -> Result<&mut Self, Self::Error> {
-        match value[0] {
-            REPORT_TYPE_DESCRIPTION => {
-                if value.len() < 10 {
-                    return Err("Not enough data.");
-                }
-                self.vid = ((value[4] as u16) << 8) | (value[3] as u16);
-                self.pid = ((value[6] as u16) << 8) | (value[5] as u16);
-                self.version = ((value[2] as u16) << 8) | (value[1] as u16);
-                self.fr_version = [value[8], (value[7] >> 4) & 15, value[7] & 15];
-                self.work_mode = value[9] & 7;
+    // Index of the active onboard profile into the per profile settings, none
+    // if the device reports a profile beyond those
+    pub fn profile_index(&self) -> Option<usize> {
+        let index = self.profile.current as usize;
+        (index < PROFILE_NUM).then_some(index)
+    }
+
+    // Settings of the active onboard profile
+    pub fn current_settings(&self) -> Option<&ReportProfileSettings> {
+        self.profile_index().map(|i| &self.profiles[i])
+    }
+
+    pub fn current_settings_mut(&mut self) -> Option<&mut ReportProfileSettings> {
+        self.profile_index().map(|i| &mut self.profiles[i])
+    }
+
+    // Effective DPI of the active onboard profile
+    pub fn current_dpi(&self) -> u16 {
+        self.current_settings()
+            .and_then(|s| self.dpi.levels_val.get(s.dpi_level as usize))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    // Effective polling rate of the active onboard profile
+    pub fn current_polling_rate(&self) -> Option<PollingRate> {
+        self.current_settings()
+            .and_then(|s| self.polling_rate.rate(s.polling_rate_level))
+    }
+
+    // Levels reported for the active onboard profile, dropped if that profile is unknown
+    fn set_current_levels(&mut self, dpi_level: u8, polling_rate_level: u8) {
+        if let Some(s) = self.current_settings_mut() {
+            s.dpi_level = dpi_level;
+            s.polling_rate_level = polling_rate_level;
+        }
+    }
+
+    // Layout the sensor features are written in, they are only laid out in byte
+    // 15 of the full layouts
+    pub fn sys_features_layout(&self) -> Option<u8> {
+        self.layout
+            .filter(|&l| l == REPORT_TYPE_FULL || l == REPORT_TYPE_FULL_SHORT)
+    }
+
+    // Whether the sleep time has been reported, the short full layout has none
+    pub fn sleep_known(&self) -> bool {
+        matches!(self.layout, Some(REPORT_TYPE_FULL | REPORT_TYPE_SETTINGS))
+    }
+
+    // Whether the sleep time can be set, it is only written in the full layout
+    pub fn can_set_sleep(&self) -> bool {
+        self.layout == Some(REPORT_TYPE_FULL)
+    }
+
+    // Sensor features supported by the device and whether they are enabled
+    pub fn features(&self) -> Vec<(Feature, bool)> {
+        let mut features = Vec::new();
+        if self.sys_features_layout().is_some() {
+            features.extend([
+                (Feature::AngleSnapping, self.sys_features.line),
+                (Feature::RippleControl, self.sys_features.wave),
+                (Feature::MotionSync, self.sys_features.motion),
+            ]);
+        }
+        if self.support.fps20k_support {
+            features.push((Feature::Fps20k, self.sys_features.fps20k));
+        }
+        features
+    }
+
+    // Wake sources supported by the device and whether they are enabled
+    pub fn wake_sources(&self) -> Vec<(WakeSource, bool)> {
+        if !self.support.rouse_origin_support {
+            return vec![];
+        }
+        let ro = &self.rouse_origin;
+        [
+            (WakeSource::Key, ro.key_support, ro.key),
+            (WakeSource::Scroll, ro.scroll_support, ro.scroll),
+            (WakeSource::Move, ro.move_support, ro.mmove),
+            (
+                WakeSource::SideScroll,
+                ro.side_scroll_support,
+                ro.side_scroll,
+            ),
+        ]
+        .into_iter()
+        .filter(|(_, supported, _)| *supported)
+        .map(|(ws, _, enabled)| (ws, enabled))
+        .collect()
+    }
+
+    // Both the description and the settings, in whichever layout, have been received
+    pub fn is_populated(&self) -> bool {
+        self.vid != 0 && self.layout.is_some()
+    }
+
+    // Checks against the limits the device reports, and those of its launcher
+    // descriptor if known
+    pub fn validate_dpi_levels(&self, levels: &[u16]) -> Result<(), InvalidDpiError> {
+        if !self.support.max_and_step_support {
+            return Err(InvalidDpiError::Unsupported);
+        }
+        self.dpi.validate_levels(levels)?;
+        if let Some((min, max)) = self
+            .keychron_device()
+            .ok()
+            .and_then(|kd| kd.descriptor())
+            .map(|d| d.dpi_limit)
+        {
+            if let Some(&v) = levels.iter().find(|&&v| v < min) {
+                return Err(InvalidDpiError::TooLow(v));
             }
-            REPORT_TYPE_FULL => {
-                if value.len() < 54 {
-                    return Err("Not enough data.");
-                }
-                self.profile.current = value[1];
-                self.profile.level_num = value[50];
-                self.dpi.level = [value[2] & 15, value[3] & 15, value[4] & 15];
-                self.dpi.levels_val = [
-                    (value[6] as u16) << 8 | (value[5] as u16),
-                    (value[8] as u16) << 8 | (value[7] as u16),
-                    (value[10] as u16) << 8 | (value[9] as u16),
-                    (value[12] as u16) << 8 | (value[11] as u16),
-                    (value[14] as u16) << 8 | (value[13] as u16),
-                ];
-                self.dpi.level_num = value[16];
-                self.dpi.level_val_max = ((value[41] as u16) << 8) | (value[40] as u16);
-                self.dpi.level_val_step = if value[42] != 0 { value[42] } else { 50 };
-                self.polling_rate.level =
-                    [value[2] >> 4 & 15, value[3] >> 4 & 15, value[4] >> 4 & 15];
-                self.polling_rate.levels_val = value[43..49].try_into().unwrap_or_default();
-                self.polling_rate.level_num = if value[49] != 0 { value[49] } else { 6 };
-                self.sys_features.lod = (value[15] & 3) != 0;
-                self.sys_features.wave = ((value[15] >> 2) & 1) != 0;
-                self.sys_features.line = ((value[15] >> 3) & 1) != 0;
-                self.sys_features.motion = ((value[15] >> 4) & 1) != 0;
-                self.sys_features.scroll_dir = ((value[15] >> 6) & 1) != 0;
-                self.sys_features.fps20k = (value[52] & 1) != 0;
-                self.debounce.value = value[17];
-                self.debounce.values = value[30..40].try_into().unwrap_or_default();
-                self.scroll.speed = value[27];
-                self.scroll.inertia = value[28];
-                self.scroll.spl = value[29];
-                self.sleep.time = value[18] as u16;
-                self.power.value = value[19] & 127;
-                self.power.state = ((value[19] >> 7) & 1) != 0;
-                self.rouse_origin.key = ((value[51] >> 4) & 1) != 0;
-                self.rouse_origin.key = ((value[51] >> 4) & 1) != 0;
-                self.rouse_origin.key_support = (value[51] & 1) != 0;
-                self.rouse_origin.scroll = ((value[51] >> 5) & 1) != 0;
-                self.rouse_origin.scroll_support = (value[51] & 2) != 0;
-                self.rouse_origin.mmove = ((value[51] >> 6) & 1) != 0;
-                self.rouse_origin.move_support = (value[51] & 4) != 0;
-                self.rouse_origin.side_scroll = ((value[51] >> 7) & 1) != 0;
-                self.rouse_origin.side_scroll_support = (value[51] & 8) != 0;
-                self.support.scroll_support = (value[26] & 1) != 0;
-                self.support.debounce_support = (value[26] & 2) != 0;
-                self.support.max_and_step_support = (value[26] & 8) != 0;
-                self.support.polling_gears_support = (value[26] & 16) != 0;
-                self.support.profile_support = (value[26] & 32) != 0;
-                self.support.rouse_origin_support = (value[26] & 64) != 0;
-                self.support.fps20k_support = (value[26] & 128) != 0;
-                //self.support.sleep_support = false; // why ?
-                self.support.loop_dpress_support = true; // why ?
-                self.support.pair_key_support = ((value[53] >> 1) & 1) != 0;
+            if let Some(&v) = levels.iter().find(|&&v| v > max) {
+                return Err(InvalidDpiError::TooHigh(v));
             }
-            REPORT_TYPE_LIGHT => {
-                if value.len() < 8 {
-                    return Err("Not enough data.");
+        }
+        Ok(())
+    }
+
+    // Update the state with a decoded report
+    pub fn apply(&mut self, packet: Packet) {
+        match packet {
+            Packet::Description(p) => {
+                if KeychronDevice::try_from(p.pid)
+                    .is_ok_and(|kd| kd.device_type() == KeychronDeviceCategory::Receiver)
+                {
+                    // the receiver describes itself apart from the mouse behind it
+                    self.receiver = Some(ReportReceiver {
+                        pid: p.pid,
+                        fr_version: p.fr_version,
+                    });
+                    return;
                 }
-                self.light.mode = value[1];
-                self.light.speed = value[3];
-                self.light.brightness = value[4];
-                self.light.rgb = [value[5], value[6], value[7]];
+                self.vid = p.vid;
+                self.pid = p.pid;
+                self.version = p.version;
+                self.fr_version = p.fr_version;
+                self.work_mode = p.work_mode;
             }
-            REPORT_TYPE_BASE => {
-                if value.len() < 8 {
-                    return Err("Not enough data.");
-                }
-                self.work_mode = value[1];
-                self.connect = value[2];
-                self.power.state = value[3] != 0;
-                self.power.value = value[4];
-                self.dpi.level = [value[5], value[5], value[5]];
-                self.dpi.level_num = value[7];
-                self.polling_rate.level = [value[6], value[6], value[6]];
+            Packet::Full(p) => {
+                self.layout = Some(REPORT_TYPE_FULL);
+                self.profile = p.profile;
+                self.profiles = p.profiles;
+                self.dpi = p.dpi;
+                self.polling_rate = p.polling_rate;
+                self.sys_features = p.sys_features;
+                self.debounce = p.debounce;
+                self.scroll = p.scroll;
+                self.sleep = p.sleep;
+                self.power = p.power;
+                self.rouse_origin = p.rouse_origin;
+                self.support = p.support;
             }
-            REPORT_TYPE_PROFILE => {
-                if value.len() < 2 {
-                    return Err("Not enough data.");
-                }
-                self.profile.current = value[1];
+            Packet::FullShort(p) => {
+                self.layout = Some(REPORT_TYPE_FULL_SHORT);
+                self.profile = ReportProfile {
+                    current: p.profile,
+                    level_num: 1,
+                };
+                self.profiles = p.profiles;
+                self.dpi.levels_val = p.dpi_levels_val;
+                self.dpi.level_num = p.dpi_level_num;
+                // the short layout has no table of the levels, so the rates
+                // stay unknown and only the level is shown
+                self.polling_rate.level_num = 0;
+                self.sys_features = ReportSysFeatures {
+                    fps20k: self.sys_features.fps20k,
+                    fps20k_raw: self.sys_features.fps20k_raw,
+                    ..p.sys_features
+                };
+                self.debounce.value = p.debounce;
             }
-            REPORT_TYPE_UNKNOWN_1 | REPORT_TYPE_UNKNOWN_65 => {
-                if value.len() < 10 {
-                    return Err("Not enough data.");
-                }
-                if (value[2] == 140 || value[2] == 142) && value[3] == 1 {
-                    self.power.state = value[5] != 0;
-                    self.power.value = value[6];
-                    self.dpi.level = [value[8], value[8], value[8]];
-                    let level = if value[9] > 0 { value[9] - 1 } else { value[9] };
-                    self.polling_rate.level = [level, level, level];
-                }
+            Packet::Light(p) => self.light = p,
+            Packet::Base(p) => {
+                self.work_mode = p.work_mode;
+                self.connect = p.connect;
+                self.power = p.power;
+                self.dpi.level_num = p.dpi_level_num;
+                self.set_current_levels(p.dpi_level, p.polling_rate_level);
             }
-            REPORT_TYPE_UNKNOWN_4 | REPORT_TYPE_UNKNOWN_68 => {
-                if value.len() < 55 {
-                    return Err("Not enough data.");
-                }
-                if value[3] == 1 {
-                    self.dpi.level = [value[7], value[7], value[7]];
-                    self.dpi.levels_val = [
-                        (value[9] as u16) << 8 | (value[8] as u16),
-                        (value[13] as u16) << 8 | (value[12] as u16),
-                        (value[17] as u16) << 8 | (value[16] as u16),
-                        (value[21] as u16) << 8 | (value[20] as u16),
-                        (value[25] as u16) << 8 | (value[24] as u16),
-                    ];
-                    self.dpi.level_num = value[6].count_ones() as u8;
-                    self.sys_features.lod = value[43] != 0;
-                    self.sys_features.wave = ((value[4] >> 4) & 1) != 0;
-                    self.sys_features.line = (value[4] & 1) != 0;
-                    self.sys_features.motion = ((value[4] >> 5) & 1) != 0;
-                    self.sys_features.scroll_dir = ((value[4] >> 7) & 1) != 0;
-                    self.debounce.value = value[54];
-                    self.sleep.time = ((value[53] as u16) << 8) | (value[52] as u16);
-                    let level = value[5].saturating_sub(1);
-                    self.polling_rate.level = [level, level, level];
-                    //self.polling_rate.levels_val = [0, 1, 2, 3, 4, 5]; // why ?
-                    //self.polling_rate.level_num = report_rate_max;
+            Packet::Button(p) => {
+                if let Some(b) = self.buttons.get_mut(p.button as usize) {
+                    *b = p.action;
                 }
             }
-            REPORT_TYPE_UNKNOWN_7 => {
-                if value.len() < 18 {
-                    return Err("Not enough data.");
-                }
-                self.profile.current = value[1];
-                self.profile.level_num = 1;
-                self.dpi.level = [value[2] & 15, value[3] & 16, value[4] & 15];
-                self.dpi.levels_val = [
-                    (value[6] as u16) << 8 | (value[5] as u16),
-                    (value[8] as u16) << 8 | (value[7] as u16),
-                    (value[10] as u16) << 8 | (value[9] as u16),
-                    (value[12] as u16) << 8 | (value[11] as u16),
-                    (value[14] as u16) << 8 | (value[13] as u16),
-                ];
-                self.dpi.level_num = value[16];
-                self.polling_rate.level =
-                    [value[2] >> 4 & 15, value[3] >> 4 & 15, value[4] >> 4 & 15];
-                //self.polling_rate.levels_val = [0, 1, 2, 0, 0, 0]; // Why is this shorter ?
-                self.polling_rate.level_num = 0;
-                self.sys_features.lod = (value[15] & 3) != 0;
-                self.sys_features.wave = (value[15] & 4) != 0;
-                self.sys_features.line = (value[15] & 8) != 0;
-                self.sys_features.motion = (value[15] & 16) != 0;
-                self.sys_features.scroll_dir = (value[15] & 64) != 0;
-                //self.sys_features.fps20k = false;
-                self.debounce.value = value[17];
+            Packet::Profile(p) => self.profile.current = p.current,
+            Packet::Status(Some(p)) => {
+                self.power = p.power;
+                self.set_current_levels(p.dpi_level, p.polling_rate_level);
+            }
+            Packet::Settings(Some(p)) => {
+                self.layout = Some(REPORT_TYPE_SETTINGS);
+                self.set_current_levels(p.dpi_level, p.polling_rate_level);
+                self.dpi.levels_val = p.dpi_levels_val;
+                self.dpi.level_num = p.dpi_level_num;
+                self.sys_features = ReportSysFeatures {
+                    fps20k: self.sys_features.fps20k,
+                    fps20k_raw: self.sys_features.fps20k_raw,
+                    ..p.sys_features
+                };
+                self.debounce.value = p.debounce;
+                self.sleep.time = p.sleep_time;
             }
-            _ => println!("Type unknown: {}", value[0]),
+            Packet::Status(None) | Packet::Settings(None) => (),
+            // followed by `KeychronHid::pair` itself
+            Packet::Pair(_) => (),
         }
+    }
+}
+
+impl TryMerge<&[u8]> for Report {
+    type Error = KeychronError;
+    fn merge(&mut self, value: &[u8]) -> Result<&mut Self, Self::Error> {
+        self.apply(packet::decode(value)?);
         Ok(self)
     }
 }
 
 impl TryFrom<&[u8]> for Report {
-    type Error = &'static str;
+    type Error = KeychronError;
     fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
         Report::default().merge(value).map(|r| r.to_owned())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::{collection::vec, prelude::*};
+
+    const REPORT_TYPES: [u8; 12] = [
+        REPORT_TYPE_STATUS,
+        REPORT_TYPE_DESCRIPTION,
+        REPORT_TYPE_SETTINGS,
+        REPORT_TYPE_FULL,
+        REPORT_TYPE_FULL_SHORT,
+        REPORT_TYPE_STATUS_RECEIVER,
+        REPORT_TYPE_SETTINGS_RECEIVER,
+        REPORT_TYPE_LIGHT,
+        REPORT_TYPE_BASE,
+        REPORT_TYPE_BUTTON,
+        REPORT_TYPE_PROFILE,
+        REPORT_TYPE_PAIR,
+    ];
+
+    proptest! {
+        // any bytes give a report or an error, never a panic
+        #[test]
+        fn try_from_is_total(value in vec(any::<u8>(), 0..64)) {
+            if let Ok(r) = Report::try_from(value.as_slice()) {
+                r.current_dpi();
+                r.current_polling_rate();
+                r.polling_rate.rates();
+                r.features();
+                r.wake_sources();
+                r.debounce.options();
+            }
+        }
+
+        // a full 63 bytes report of a known type always decodes
+        #[test]
+        fn known_types_decode(
+            report_type in prop::sample::select(REPORT_TYPES.to_vec()),
+            pair_state in 0u8..4,
+            rest in vec(any::<u8>(), 62),
+        ) {
+            let mut value = vec![report_type];
+            value.extend(rest);
+            // only the pair state has values it can't take
+            if report_type == REPORT_TYPE_PAIR {
+                value[1] = pair_state;
+            }
+            prop_assert!(Report::try_from(value.as_slice()).is_ok());
+        }
+
+        #[test]
+        fn unknown_types_rejected(
+            report_type in any::<u8>().prop_filter("known type", |t| !REPORT_TYPES.contains(t)),
+            rest in vec(any::<u8>(), 0..63),
+        ) {
+            let mut value = vec![report_type];
+            value.extend(rest);
+            let res = Report::try_from(value.as_slice());
+            prop_assert!(
+                matches!(res, Err(KeychronError::UnknownReport { report_type: t }) if t == report_type),
+                "{:?}",
+                res
+            );
+        }
+    }
+
+    #[test]
+    fn sys_features_keep_unknown_bits() {
+        let sys_features = ReportSysFeatures {
+            lod: Some(Lod::Mm2_0),
+            motion: true,
+            flags_raw: 0b1011_0001,
+            ..Default::default()
+        };
+        assert_eq!(sys_features.flags(), 0b1011_0010);
+        // an unknown lift off distance is left alone too
+        let sys_features = ReportSysFeatures {
+            flags_raw: 0b0000_0011,
+            ..Default::default()
+        };
+        assert_eq!(sys_features.flags(), 0b0000_0011);
+        let sys_features = ReportSysFeatures {
+            fps20k: true,
+            fps20k_raw: 0b0100_0000,
+            ..Default::default()
+        };
+        assert_eq!(sys_features.fps20k_flags(), 0b0100_0001);
+    }
+
+    #[test]
+    fn wake_sources_keep_support_bits() {
+        let rouse_origin = ReportRouseOrigin {
+            scroll: true,
+            // support reported without the matching decoded field set
+            flags_raw: 0b1001_0101,
+            ..Default::default()
+        };
+        assert_eq!(rouse_origin.flags(), 0b0010_0101);
+    }
+
+    #[test]
+    fn sleep_time_shown() {
+        assert_eq!(ReportSleep { time: 0 }.to_string(), "never");
+        assert_eq!(ReportSleep { time: 5 }.to_string(), "5 min");
+        assert_eq!(ReportSleep { time: 60 }.to_string(), "1 h");
+        assert_eq!(ReportSleep { time: 90 }.to_string(), "1 h 30 min");
+    }
+
+    #[test]
+    fn polling_rate_levels() {
+        for (level, hz) in [125, 500, 1000, 2000, 4000, 8000].into_iter().enumerate() {
+            let rate = PollingRate::try_from(level as u8).unwrap();
+            assert_eq!(rate.hz(), hz);
+            assert_eq!(rate.to_string().parse(), Ok(rate));
+        }
+        assert!(PollingRate::try_from(6).is_err());
+    }
+
+    #[test]
+    fn polling_rates_of_levels() {
+        // no table reported, the levels are the rates in ascending order
+        let p = ReportPollingRate {
+            levels_val: [0; 6],
+            level_num: 3,
+        };
+        assert_eq!(
+            p.rates(),
+            [PollingRate::Hz125, PollingRate::Hz500, PollingRate::Hz1000]
+        );
+        assert_eq!(p.rate(3), None);
+        let p = ReportPollingRate {
+            levels_val: [2, 5, 0, 0, 0, 0],
+            level_num: 2,
+        };
+        assert_eq!(p.rates(), [PollingRate::Hz1000, PollingRate::Hz8000]);
+        // a short layout reports no levels
+        assert!(ReportPollingRate::default().rates().is_empty());
+    }
+
+    #[test]
+    fn dpi_levels_validated() {
+        let dpi = ReportDPI {
+            level_val_max: 26000,
+            level_val_step: 50,
+            ..Default::default()
+        };
+        assert_eq!(dpi.validate_levels(&[DPI_LEVEL_VAL_MIN, 26000]), Ok(()));
+        assert_eq!(
+            dpi.validate_levels(&[DPI_LEVEL_VAL_MIN - 1]),
+            Err(InvalidDpiError::TooLow(DPI_LEVEL_VAL_MIN - 1))
+        );
+        assert_eq!(
+            dpi.validate_levels(&[26050]),
+            Err(InvalidDpiError::TooHigh(26050))
+        );
+        assert_eq!(
+            dpi.validate_levels(&[800, 825]),
+            Err(InvalidDpiError::Step(825))
+        );
+        assert_eq!(dpi.validate_levels(&[]), Err(InvalidDpiError::LevelNum(0)));
+        assert_eq!(
+            dpi.validate_levels(&[800; 6]),
+            Err(InvalidDpiError::LevelNum(6))
+        );
+        // no limits reported, only the minimum applies
+        assert_eq!(ReportDPI::default().validate_levels(&[30025]), Ok(()));
+    }
+
+    #[test]
+    fn dpi_levels_need_max_and_step_support() {
+        let mut r = Report::default();
+        r.dpi.level_val_max = 26000;
+        assert_eq!(
+            r.validate_dpi_levels(&[30000]),
+            Err(InvalidDpiError::Unsupported)
+        );
+        r.support.max_and_step_support = true;
+        assert_eq!(
+            r.validate_dpi_levels(&[30000]),
+            Err(InvalidDpiError::TooHigh(30000))
+        );
+        assert_eq!(r.validate_dpi_levels(&[1600]), Ok(()));
+        // the descriptor of the M6 8K caps it at 30000
+        r.pid = KeychronDevice::M6_8K.into();
+        r.dpi.level_val_max = 40000;
+        assert_eq!(
+            r.validate_dpi_levels(&[30050]),
+            Err(InvalidDpiError::TooHigh(30050))
+        );
+        assert_eq!(r.validate_dpi_levels(&[30000]), Ok(()));
+    }
+
+    #[test]
+    fn populated_by_any_settings_layout() {
+        for layout in [
+            REPORT_TYPE_FULL,
+            REPORT_TYPE_FULL_SHORT,
+            REPORT_TYPE_SETTINGS,
+        ] {
+            let mut r = Report::default();
+            r.merge(&[
+                REPORT_TYPE_DESCRIPTION,
+                1,
+                1,
+                0x34,
+                0x34,
+                0x49,
+                0xd0,
+                1,
+                1,
+                1,
+            ])
+            .unwrap();
+            assert!(!r.is_populated());
+            let mut settings = vec![0u8; 63];
+            settings[0] = layout;
+            // the short settings only hold values with this flag
+            settings[3] = 1;
+            r.merge(&settings).unwrap();
+            assert!(r.is_populated(), "layout {}", layout);
+            assert_eq!(r.sleep_known(), layout != REPORT_TYPE_FULL_SHORT);
+            assert_eq!(r.can_set_sleep(), layout == REPORT_TYPE_FULL);
+        }
+    }
+
+    // a report cut short is rejected with its type and leaves the state alone
+    #[test]
+    fn truncated_reports_rejected() {
+        for report_type in REPORT_TYPES {
+            let mut r = Report::default();
+            r.profile.current = 2;
+            let before = r;
+            assert!(matches!(
+                r.merge(&[report_type]),
+                Err(KeychronError::ProtocolError { report_type: t }) if t == report_type
+            ));
+            assert_eq!(r, before);
+        }
+    }
+
+    #[test]
+    fn empty_report_rejected() {
+        assert!(matches!(
+            Report::try_from(&[][..]),
+            Err(KeychronError::EmptyReport)
+        ));
+    }
+}
@@ -0,0 +1,238 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    ffi::CString,
+    sync::{Arc, Condvar, Mutex},
+    time::Duration,
+};
+
+use hidapi::HidError;
+
+use crate::{
+    keychron_device::{KeychronDevice, KeychronDeviceCategory},
+    keychron_hid::{
+        CMD_GET, CMD_INFO, CMD_SET, KEYCHRON_USAGE, KEYCHRON_USAGE_PAGE, KEYCHRON_VENDOR_ID,
+    },
+    report::{PairState, REPORT_TYPE_DESCRIPTION, REPORT_TYPE_FULL, REPORT_TYPE_PAIR},
+    transport::{DeviceInfo, Transport, TransportDevice},
+};
+
+// In-memory devices answering from canned reports, to run without hardware
+pub struct ScriptedTransport {
+    devices: Vec<(DeviceInfo, Arc<ScriptedDevice>)>,
+}
+
+impl ScriptedTransport {
+    pub fn new(devices: Vec<(DeviceInfo, Arc<ScriptedDevice>)>) -> Self {
+        ScriptedTransport { devices }
+    }
+
+    // A wired M6 8K, and an M6 4K behind an Ultra-Link 8K
+    pub fn simulated() -> Self {
+        let receiver = KeychronDevice::UltraLink8K;
+        Self::new(vec![
+            (
+                Self::info(KeychronDevice::M6_8K),
+                Arc::new(ScriptedDevice::new([
+                    Self::description(KeychronDevice::M6_8K, 1),
+                    Self::full(),
+                ])),
+            ),
+            (
+                Self::info(receiver),
+                Arc::new(ScriptedDevice::new([
+                    Self::description(receiver, 2),
+                    Self::description(KeychronDevice::M6_4K, 2),
+                    Self::full(),
+                ])),
+            ),
+        ])
+    }
+
+    fn info(kd: KeychronDevice) -> DeviceInfo {
+        DeviceInfo {
+            path: CString::new(format!("scripted:{}", kd)).unwrap_or_default(),
+            vendor_id: KEYCHRON_VENDOR_ID,
+            product_id: kd.into(),
+            usage_page: KEYCHRON_USAGE_PAGE,
+            usage: KEYCHRON_USAGE,
+            usb: true,
+        }
+    }
+
+    // Version 1.0.1 in the given work mode
+    fn description(kd: KeychronDevice, work_mode: u8) -> Vec<u8> {
+        let vid = KEYCHRON_VENDOR_ID.to_le_bytes();
+        let pid = u16::from(kd).to_le_bytes();
+        vec![
+            REPORT_TYPE_DESCRIPTION,
+            1,
+            1,
+            vid[0],
+            vid[1],
+            pid[0],
+            pid[1],
+            0x01,
+            1,
+            work_mode,
+        ]
+    }
+
+    fn full() -> Vec<u8> {
+        let mut full = vec![0u8; 63];
+        full[0] = REPORT_TYPE_FULL;
+        // profile 1, 800 dpi and 1000 Hz in every profile
+        full[2..5].copy_from_slice(&[0x21, 0x21, 0x21]);
+        for (i, dpi) in [400u16, 800, 1600, 3200, 6400].iter().enumerate() {
+            full[5 + i * 2..7 + i * 2].copy_from_slice(&dpi.to_le_bytes());
+        }
+        // 1.0mm LOD and motion sync
+        full[15] = 0x11;
+        full[16] = 5;
+        full[17] = 4;
+        full[18] = 5;
+        full[19] = 80;
+        full[26] = 0xfb;
+        full[27..30].copy_from_slice(&[5, 0, 3]);
+        full[30..40].copy_from_slice(&[2, 4, 6, 8, 10, 12, 14, 16, 18, 20]);
+        full[40..42].copy_from_slice(&26000u16.to_le_bytes());
+        full[42] = 50;
+        full[43..49].copy_from_slice(&[0, 1, 2, 3, 4, 5]);
+        full[49] = 6;
+        full[50] = 3;
+        // wakes on key, scroll and move, side scroll supported too
+        full[51] = 0x7f;
+        full
+    }
+}
+
+impl Transport for ScriptedTransport {
+    fn enumerate(&mut self, vendor_id: u16) -> Result<Vec<DeviceInfo>, HidError> {
+        Ok(self
+            .devices
+            .iter()
+            .filter(|(d, _)| d.vendor_id == vendor_id)
+            .map(|(d, _)| d.clone())
+            .collect())
+    }
+
+    fn open(&self, dev: &DeviceInfo) -> Result<Box<dyn TransportDevice>, HidError> {
+        self.devices
+            .iter()
+            .find(|(d, _)| d.path == dev.path)
+            .map(|(_, sd)| Box::new(ScriptedHandle(sd.clone())) as Box<dyn TransportDevice>)
+            .ok_or(HidError::HidApiError {
+                message: format!("No scripted device at {:?}", dev.path),
+            })
+    }
+}
+
+// Reports by type, requests are answered with them and writes patch them
+pub struct ScriptedDevice {
+    reports: Mutex<HashMap<u8, Vec<u8>>>,
+    // descriptions by the ttype they answer, see `KeychronHid::poke_device`
+    infos: HashMap<u8, Vec<u8>>,
+    queue: Mutex<VecDeque<Vec<u8>>>,
+    ready: Condvar,
+}
+
+impl ScriptedDevice {
+    // Reports without the report id, starting with their type. The first
+    // description is the device's own, a second one that of the mouse behind it
+    pub fn new(reports: impl IntoIterator<Item = Vec<u8>>) -> Self {
+        let (descriptions, reports): (Vec<Vec<u8>>, Vec<Vec<u8>>) = reports
+            .into_iter()
+            .filter(|r| !r.is_empty())
+            .partition(|r| r[0] == REPORT_TYPE_DESCRIPTION);
+        ScriptedDevice {
+            reports: Mutex::new(reports.into_iter().map(|r| (r[0], r)).collect()),
+            infos: [
+                KeychronDeviceCategory::Receiver,
+                KeychronDeviceCategory::Mouse,
+            ]
+            .into_iter()
+            .map(|category| category.ttype())
+            .zip(descriptions)
+            .collect(),
+            queue: Mutex::new(VecDeque::new()),
+            ready: Condvar::new(),
+        }
+    }
+
+    // Send a report unrequested, like a device does on a button press
+    pub fn push(&self, report: &[u8]) {
+        let mut packet = vec![0u8];
+        packet.extend_from_slice(report);
+        if let Ok(mut queue) = self.queue.lock() {
+            queue.push_back(packet);
+            self.ready.notify_all();
+        }
+    }
+
+    fn handle(&self, data: &[u8]) {
+        let Ok(mut reports) = self.reports.lock() else {
+            return;
+        };
+        let reply = match data {
+            [CMD_INFO, ttype, ..] => self.infos.get(ttype).cloned(),
+            [CMD_GET, report_type, ..] => reports.get(report_type).cloned(),
+            // a mouse pairs as soon as the receiver waits for one
+            [CMD_SET, REPORT_TYPE_PAIR, _, _, state, ..]
+                if *state == u8::from(PairState::Pairing) =>
+            {
+                Some(vec![REPORT_TYPE_PAIR, PairState::Paired.into()])
+            }
+            [CMD_SET, report_type, offset, len, rest @ ..] => {
+                let (offset, len) = (*offset as usize, (*len as usize).min(rest.len()));
+                if let Some(r) = reports.get_mut(report_type) {
+                    if r.len() < offset + len {
+                        r.resize(offset + len, 0);
+                    }
+                    r[offset..offset + len].copy_from_slice(&rest[..len]);
+                }
+                None
+            }
+            _ => None,
+        };
+        drop(reports);
+        if let Some(reply) = reply {
+            self.push(&reply);
+        }
+    }
+
+    fn pop(&self, buf: &mut [u8], timeout: Option<Duration>) -> usize {
+        let Ok(mut queue) = self.queue.lock() else {
+            return 0;
+        };
+        while queue.is_empty() {
+            queue = match timeout {
+                Some(t) => match self.ready.wait_timeout(queue, t) {
+                    Ok((q, r)) if !r.timed_out() => q,
+                    _ => return 0,
+                },
+                None => match self.ready.wait(queue) {
+                    Ok(q) => q,
+                    Err(_) => return 0,
+                },
+            };
+        }
+        let packet = queue.pop_front().unwrap_or_default();
+        let len = packet.len().min(buf.len());
+        buf[..len].copy_from_slice(&packet[..len]);
+        len
+    }
+}
+
+struct ScriptedHandle(Arc<ScriptedDevice>);
+
+impl TransportDevice for ScriptedHandle {
+    fn read_timeout(&self, buf: &mut [u8], timeout_ms: i32) -> Result<usize, HidError> {
+        // negative waits like a blocking read
+        let timeout = u64::try_from(timeout_ms).ok().map(Duration::from_millis);
+        Ok(self.0.pop(buf, timeout))
+    }
+
+    fn write(&self, data: &[u8]) -> Result<usize, HidError> {
+        self.0.handle(data);
+        Ok(data.len())
+    }
+}
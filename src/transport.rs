@@ -0,0 +1,100 @@
+use std::ffi::CString;
+#[cfg(target_os = "linux")]
+use std::os::unix::ffi::OsStrExt;
+
+use hidapi::{BusType, HidApi, HidDevice, HidError};
+
+// A HID interface as found by `Transport::enumerate`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DeviceInfo {
+    pub path: CString,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub usage_page: u16,
+    pub usage: u16,
+    pub usb: bool,
+}
+
+// Finds and opens devices
+pub trait Transport: Send + Sync {
+    fn enumerate(&mut self, vendor_id: u16) -> Result<Vec<DeviceInfo>, HidError>;
+    fn open(&self, dev: &DeviceInfo) -> Result<Box<dyn TransportDevice>, HidError>;
+}
+
+// An opened device, reads return the report id in the first byte
+pub trait TransportDevice: Send {
+    fn read_timeout(&self, buf: &mut [u8], timeout_ms: i32) -> Result<usize, HidError>;
+    fn write(&self, data: &[u8]) -> Result<usize, HidError>;
+}
+
+impl<T: TransportDevice + ?Sized> TransportDevice for Box<T> {
+    fn read_timeout(&self, buf: &mut [u8], timeout_ms: i32) -> Result<usize, HidError> {
+        (**self).read_timeout(buf, timeout_ms)
+    }
+
+    fn write(&self, data: &[u8]) -> Result<usize, HidError> {
+        (**self).write(data)
+    }
+}
+
+pub struct HidTransport {
+    hid_api: HidApi,
+}
+
+impl HidTransport {
+    pub fn new() -> Result<Self, HidError> {
+        HidApi::disable_device_discovery();
+        let hid_api = HidApi::new()?;
+        Ok(HidTransport { hid_api })
+    }
+}
+
+impl Transport for HidTransport {
+    fn enumerate(&mut self, vendor_id: u16) -> Result<Vec<DeviceInfo>, HidError> {
+        self.hid_api.reset_devices()?;
+        self.hid_api.add_devices(vendor_id, 0)?;
+        Ok(self
+            .hid_api
+            .device_list()
+            .map(|d| DeviceInfo {
+                path: d.path().to_owned(),
+                vendor_id: d.vendor_id(),
+                product_id: d.product_id(),
+                usage_page: d.usage_page(),
+                usage: d.usage(),
+                usb: d.bus_type() as u8 == BusType::Usb as u8,
+            })
+            .collect())
+    }
+
+    fn open(&self, dev: &DeviceInfo) -> Result<Box<dyn TransportDevice>, HidError> {
+        match self.hid_api.open_path(&dev.path) {
+            Ok(hid_dev) => Ok(Box::new(hid_dev)),
+            // the C backends only give a message for why opening failed, the hidraw
+            // node is opened once more for the OS error, where missing udev rules show
+            #[cfg(target_os = "linux")]
+            Err(e) => match std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(std::ffi::OsStr::from_bytes(dev.path.as_bytes()))
+            {
+                Err(error) if error.kind() == std::io::ErrorKind::PermissionDenied => {
+                    Err(HidError::IoError { error })
+                }
+                _ => Err(e),
+            },
+            #[cfg(not(target_os = "linux"))]
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl TransportDevice for HidDevice {
+    fn read_timeout(&self, buf: &mut [u8], timeout_ms: i32) -> Result<usize, HidError> {
+        HidDevice::read_timeout(self, buf, timeout_ms)
+    }
+
+    fn write(&self, data: &[u8]) -> Result<usize, HidError> {
+        HidDevice::write(self, data)
+    }
+}
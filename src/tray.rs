@@ -1,21 +1,47 @@
-use std::{error, fmt, process::exit, str, sync::Arc};
+#[cfg(target_os = "linux")]
+use std::error;
+#[cfg(any(test, feature = "simulate"))]
+use std::time::Duration;
+use std::{
+    collections::{BTreeMap, HashSet},
+    process::exit,
+    sync::Arc,
+};
 
 use notify_rust::Notification;
 #[cfg(target_os = "linux")]
 use notify_rust::Timeout;
-use num_enum::{IntoPrimitive, TryFromPrimitive};
 use tokio::sync::{Mutex, mpsc};
 use trayicon::{Icon, MenuBuilder, TrayIcon, TrayIconBuilder, TrayIconStatus};
 
+#[cfg(target_os = "linux")]
+use crate::error::KeychronError;
 #[cfg(target_os = "linux")]
 use crate::udev;
+use crate::{
+    keychron_hid::{Command, DefaultSetting},
+    report::{
+        Feature, LOD_OPTIONS, Lod, PollingRate, ReportDebounce, ReportScroll, ReportSleep,
+        SCROLL_INERTIA_MAX, SCROLL_LINES_MAX, SCROLL_SPEED_MAX, SLEEP_TIME_PRESETS, WakeSource,
+    },
+    update::{self, FirmwareRelease},
+};
 
-const KEYCHRON_URL: &str = "https://launcher.keychron.com";
 const ICON_NORMAL_BYTES: &[u8] = include_bytes!("../assets/Keychron_icon.ico");
 const ICON_BAT_FULL_BYTES: &[u8] = include_bytes!("../assets/Keychron_icon_bat_full.ico");
 const ICON_BAT_GOOD_BYTES: &[u8] = include_bytes!("../assets/Keychron_icon_bat_good.ico");
 const ICON_BAT_HALF_BYTES: &[u8] = include_bytes!("../assets/Keychron_icon_bat_half.ico");
 const ICON_BAT_LOW_BYTES: &[u8] = include_bytes!("../assets/Keychron_icon_bat_low.ico");
+// The same icons with a badge for a firmware update
+const ICON_UPDATE_BYTES: &[u8] = include_bytes!("../assets/Keychron_icon_update.ico");
+const ICON_BAT_FULL_UPDATE_BYTES: &[u8] =
+    include_bytes!("../assets/Keychron_icon_bat_full_update.ico");
+const ICON_BAT_GOOD_UPDATE_BYTES: &[u8] =
+    include_bytes!("../assets/Keychron_icon_bat_good_update.ico");
+const ICON_BAT_HALF_UPDATE_BYTES: &[u8] =
+    include_bytes!("../assets/Keychron_icon_bat_half_update.ico");
+const ICON_BAT_LOW_UPDATE_BYTES: &[u8] =
+    include_bytes!("../assets/Keychron_icon_bat_low_update.ico");
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Default)]
 enum TrayEvent {
@@ -27,94 +53,90 @@ enum TrayEvent {
     Close,
     LeftClick,
     RightClick,
-}
-
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Default, IntoPrimitive, TryFromPrimitive)]
-#[repr(u8)]
-enum PollLevel {
-    #[default]
-    Level0,
-    Level1,
-    Level2,
-    Level3,
-    Level4,
-    Level5,
-}
-
-#[derive(Debug, Eq, PartialEq, Copy, Clone, Hash)]
-pub struct ParsePollLevelError;
-
-impl fmt::Display for ParsePollLevelError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        f.write_str("Invalid poll level given")
-    }
-}
-
-impl error::Error for ParsePollLevelError {
-    fn description(&self) -> &str {
-        "invalid poll level"
-    }
-}
-
-impl fmt::Display for PollLevel {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match *self {
-            PollLevel::Level0 => f.write_str("⚪"),
-            PollLevel::Level1 => f.write_str("🔵"),
-            PollLevel::Level2 => f.write_str("🔴"),
-            PollLevel::Level3 => f.write_str("⚪🔵"),
-            PollLevel::Level4 => f.write_str("⚪🔴"),
-            PollLevel::Level5 => f.write_str("⚪🔵🔴"),
-        }
-    }
-}
-
-impl str::FromStr for PollLevel {
-    type Err = ParsePollLevelError;
-
-    fn from_str(s: &str) -> ::std::result::Result<Self, Self::Err> {
-        match s.replace(" ", "").to_uppercase().as_str() {
-            "⚪" => Ok(PollLevel::Level0),
-            "🔵" => Ok(PollLevel::Level1),
-            "🔴" => Ok(PollLevel::Level2),
-            "⚪🔵" => Ok(PollLevel::Level3),
-            "⚪🔴" => Ok(PollLevel::Level4),
-            "⚪🔵🔴" => Ok(PollLevel::Level5),
-            _ => Err(ParsePollLevelError),
-        }
-    }
+    // Confirm restoring the defaults of the device with the given id
+    #[cfg(target_os = "linux")]
+    RestoreDefaults(usize),
+    // Command for the device with the given id
+    Command(usize, Command),
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Default)]
 pub struct Device {
     pub name: String,
+    // Product id of the mouse, also when it reports through a receiver
+    pub product_id: u16,
     pub version: String,
     pub battery: u8,
     pub charging: bool,
     pub dpi: u16,
-    pub polling_rate_level: u8,
+    pub dpi_level: Option<u8>,
+    pub dpi_levels: Vec<u16>,
+    pub polling_rate: Option<PollingRate>,
+    pub polling_rate_level: Option<u8>,
+    pub polling_rates: Vec<PollingRate>,
+    pub profile: u8,
+    pub profile_num: u8,
+    pub debounce: Option<ReportDebounce>,
+    pub sleep: Option<ReportSleep>,
+    // Only shown when the sleep time can't be set
+    pub sleep_settable: bool,
+    pub lod: Option<Lod>,
+    pub features: Vec<(Feature, bool)>,
+    pub scroll: Option<ReportScroll>,
+    pub scroll_natural: Option<bool>,
+    pub wake_sources: Vec<(WakeSource, bool)>,
+    #[cfg(any(test, feature = "simulate"))]
+    pub pair: bool,
+    // Whether the defaults of the model are known
+    pub defaults: bool,
+    // Name and firmware version of the receiver the mouse is connected through
+    pub receiver: Option<(String, String)>,
+}
+
+struct TrayDevice {
+    dev: Device,
+    changes: usize,
+    firmware: Option<FirmwareRelease>,
+    // Time left to pair a mouse to the receiver
+    #[cfg(any(test, feature = "simulate"))]
+    pairing: Option<Duration>,
 }
 
 pub struct Tray {
     tray_icon: Arc<Mutex<TrayIcon<TrayEvent>>>,
     icon: Icon,
     bat_icons: [Icon; 4],
-    dev: Option<Device>,
+    update_icon: Icon,
+    update_bat_icons: [Icon; 4],
+    devs: BTreeMap<usize, TrayDevice>,
     #[cfg(target_os = "linux")]
     install_udev_rules: bool,
-    changes: usize,
+    // devices and versions notified about, to not repeat it on every reconnect
+    firmware_notified: HashSet<(String, String)>,
+    // releases found before the device was first shown
+    pending_firmware: BTreeMap<usize, FirmwareRelease>,
+    // devices and errors notified about, to not repeat it on every retry
+    errors_notified: HashSet<(String, String)>,
 }
 
 unsafe impl Send for Tray {}
 unsafe impl Sync for Tray {}
 
 impl Tray {
-    pub fn new() -> Result<Self, Box<dyn std::error::Error>> {
+    pub fn new(cmd_tx: mpsc::Sender<(usize, Command)>) -> Result<Self, Box<dyn std::error::Error>> {
         let icon_normal = Icon::from_buffer(ICON_NORMAL_BYTES, None, None).unwrap();
         let icon_bat_full = Icon::from_buffer(ICON_BAT_FULL_BYTES, None, None).unwrap();
         let icon_bat_good = Icon::from_buffer(ICON_BAT_GOOD_BYTES, None, None).unwrap();
         let icon_bat_half = Icon::from_buffer(ICON_BAT_HALF_BYTES, None, None).unwrap();
         let icon_bat_low = Icon::from_buffer(ICON_BAT_LOW_BYTES, None, None).unwrap();
+        let icon_update = Icon::from_buffer(ICON_UPDATE_BYTES, None, None).unwrap();
+        let icon_bat_full_update =
+            Icon::from_buffer(ICON_BAT_FULL_UPDATE_BYTES, None, None).unwrap();
+        let icon_bat_good_update =
+            Icon::from_buffer(ICON_BAT_GOOD_UPDATE_BYTES, None, None).unwrap();
+        let icon_bat_half_update =
+            Icon::from_buffer(ICON_BAT_HALF_UPDATE_BYTES, None, None).unwrap();
+        let icon_bat_low_update = Icon::from_buffer(ICON_BAT_LOW_UPDATE_BYTES, None, None).unwrap();
         let (tx, mut rx) = mpsc::channel::<TrayEvent>(1);
         let tray_icon = Arc::new(Mutex::new(
             TrayIconBuilder::new()
@@ -150,7 +172,24 @@ impl Tray {
                     }
                     TrayEvent::Configure => {
                         tokio::task::spawn_blocking(move || {
-                            webbrowser::open(KEYCHRON_URL).ok();
+                            webbrowser::open(&update::launcher_url()).ok();
+                        });
+                    }
+                    TrayEvent::Command(id, cmd) => {
+                        cmd_tx.send((id, cmd)).await.ok();
+                    }
+                    #[cfg(target_os = "linux")]
+                    TrayEvent::RestoreDefaults(id) => {
+                        let cmd_tx = cmd_tx.clone();
+                        tokio::spawn(async move {
+                            // waiting for the action blocks
+                            let confirmed =
+                                tokio::task::spawn_blocking(Self::confirm_restore_defaults)
+                                    .await
+                                    .unwrap_or(false);
+                            if confirmed {
+                                cmd_tx.send((id, Command::RestoreDefaults)).await.ok();
+                            }
                         });
                     }
                     #[cfg(target_os = "linux")]
@@ -167,10 +206,19 @@ impl Tray {
             tray_icon,
             icon: icon_normal,
             bat_icons: [icon_bat_low, icon_bat_half, icon_bat_good, icon_bat_full],
-            dev: None,
+            update_icon: icon_update,
+            update_bat_icons: [
+                icon_bat_low_update,
+                icon_bat_half_update,
+                icon_bat_good_update,
+                icon_bat_full_update,
+            ],
+            devs: BTreeMap::new(),
             #[cfg(target_os = "linux")]
             install_udev_rules: false,
-            changes: 0,
+            firmware_notified: HashSet::new(),
+            pending_firmware: BTreeMap::new(),
+            errors_notified: HashSet::new(),
         })
     }
 
@@ -180,9 +228,45 @@ impl Tray {
         if self.install_udev_rules {
             mb = mb.item("Install udev rules", TrayEvent::UdevRules);
         }
-        if let Some(dev) = &self.dev {
+        for (&id, td) in self.devs.iter().filter(|(_, td)| !self.duplicate(td)) {
+            let dev = &td.dev;
+            mb = match &dev.receiver {
+                Some((receiver, _)) => mb.item(
+                    format!("🖱️{} ⇄ {}", dev.name, receiver).as_str(),
+                    TrayEvent::None,
+                ),
+                None => mb.item(format!("🖱️{}", dev.name).as_str(), TrayEvent::None),
+            };
+            if dev.profile_num > 0 {
+                mb = mb.submenu(
+                    format!("┣👤Profile {}", dev.profile + 1).as_str(),
+                    (0..dev.profile_num).fold(MenuBuilder::new(), |mb, p| {
+                        mb.checkable(
+                            format!("Profile {}", p + 1).as_str(),
+                            p == dev.profile,
+                            TrayEvent::Command(id, Command::Profile(p)),
+                        )
+                    }),
+                );
+            }
+            let mut dpi_menu =
+                dev.dpi_levels
+                    .iter()
+                    .enumerate()
+                    .fold(MenuBuilder::new(), |mb, (i, v)| {
+                        mb.checkable(
+                            format!("{} dpi", v).as_str(),
+                            Some(i as u8) == dev.dpi_level,
+                            TrayEvent::Command(id, Command::DpiLevel(i as u8)),
+                        )
+                    });
+            if dev.defaults {
+                dpi_menu = dpi_menu.separator().item(
+                    "Restore defaults",
+                    TrayEvent::Command(id, Command::RestoreDefault(DefaultSetting::DpiLevels)),
+                );
+            }
             mb = mb
-                .item(format!("🖱️{}", dev.name).as_str(), TrayEvent::None)
                 .item(
                     format!(
                         "┣{}{}%",
@@ -196,35 +280,302 @@ impl Tray {
                     .as_str(),
                     TrayEvent::None,
                 )
-                .item(format!("┣📏{} dpi", dev.dpi).as_str(), TrayEvent::None)
-                .item(
+                .submenu(format!("┣📏{} dpi", dev.dpi).as_str(), dpi_menu)
+                .submenu(
                     format!(
-                        "┣⏱{}:{}",
-                        dev.polling_rate_level,
-                        TryInto::<PollLevel>::try_into(dev.polling_rate_level).unwrap_or_default()
+                        "┣⏱{}",
+                        // without the rates of the levels, show the level like before
+                        match (dev.polling_rate, dev.polling_rate_level) {
+                            (Some(r), _) => r.to_string(),
+                            (None, Some(l)) => format!("level {}", l),
+                            (None, None) => "?".to_string(),
+                        }
                     )
                     .as_str(),
+                    dev.polling_rates.iter().fold(MenuBuilder::new(), |mb, r| {
+                        mb.checkable(
+                            r.to_string().as_str(),
+                            Some(*r) == dev.polling_rate,
+                            TrayEvent::Command(id, Command::PollingRate(*r)),
+                        )
+                    }),
+                );
+            if let Some(debounce) = &dev.debounce {
+                mb = mb.submenu(
+                    format!("┣⌛{} ms debounce", debounce.value).as_str(),
+                    debounce
+                        .options()
+                        .into_iter()
+                        .fold(MenuBuilder::new(), |mb, v| {
+                            mb.checkable(
+                                format!("{} ms", v).as_str(),
+                                v == debounce.value,
+                                TrayEvent::Command(id, Command::Debounce(v)),
+                            )
+                        }),
+                );
+            }
+            if let Some(lod) = dev.lod {
+                mb = mb.submenu(
+                    format!("┣↕{} LOD", lod).as_str(),
+                    LOD_OPTIONS.iter().fold(MenuBuilder::new(), |mb, &l| {
+                        mb.checkable(
+                            l.to_string().as_str(),
+                            l == lod,
+                            TrayEvent::Command(id, Command::Lod(l)),
+                        )
+                    }),
+                );
+            }
+            if !dev.features.is_empty() {
+                mb = mb.submenu(
+                    "┣🎯Sensor",
+                    dev.features
+                        .iter()
+                        .fold(MenuBuilder::new(), |mb, &(feature, enabled)| {
+                            mb.checkable(
+                                feature.to_string().as_str(),
+                                enabled,
+                                TrayEvent::Command(id, Command::ToggleFeature(feature)),
+                            )
+                        }),
+                );
+            }
+            if dev.scroll_natural.is_some() || dev.scroll.is_some() {
+                mb = mb.submenu("┣🛞Scroll", Self::gen_scroll_menu(id, dev));
+            }
+            if let Some(sleep) = dev.sleep.filter(|_| !dev.sleep_settable) {
+                mb = mb.item(format!("┣💤{}", sleep).as_str(), TrayEvent::None);
+            } else if let Some(sleep) = &dev.sleep {
+                mb = mb.submenu(
+                    format!("┣💤{}", sleep).as_str(),
+                    SLEEP_TIME_PRESETS
+                        .iter()
+                        .fold(MenuBuilder::new(), |mb, &time| {
+                            mb.checkable(
+                                ReportSleep { time }.to_string().as_str(),
+                                time == sleep.time,
+                                TrayEvent::Command(id, Command::SleepTime(time)),
+                            )
+                        }),
+                );
+            }
+            if !dev.wake_sources.is_empty() {
+                mb = mb.submenu(
+                    "┣⏰Wake up on",
+                    dev.wake_sources
+                        .iter()
+                        .fold(MenuBuilder::new(), |mb, &(source, enabled)| {
+                            mb.checkable(
+                                source.to_string().as_str(),
+                                enabled,
+                                TrayEvent::Command(id, Command::ToggleWakeSource(source)),
+                            )
+                        }),
+                );
+            }
+            #[cfg(any(test, feature = "simulate"))]
+            if let Some(left) = td.pairing {
+                mb = mb.item(
+                    format!("┣🔗Pairing, {} s left", left.as_secs()).as_str(),
                     TrayEvent::None,
-                )
-                .item(format!("┗🛈{}", dev.version).as_str(), TrayEvent::None)
+                );
+            } else if dev.pair {
+                mb = mb.item("┣🔗Pair new device", TrayEvent::Command(id, Command::Pair));
+            }
+            // confirmed through a notification action where there are some, else
+            // through a submenu
+            if dev.defaults {
+                #[cfg(target_os = "linux")]
+                {
+                    mb = mb.item(
+                        "┣♻Restore default DPI levels",
+                        TrayEvent::RestoreDefaults(id),
+                    );
+                }
+                #[cfg(not(target_os = "linux"))]
+                {
+                    mb = mb.submenu(
+                        "┣♻Restore default DPI levels",
+                        MenuBuilder::new()
+                            .item("Confirm", TrayEvent::Command(id, Command::RestoreDefaults)),
+                    );
+                }
+            }
+            if let Some(firmware) = &td.firmware {
+                mb = mb.item(
+                    format!("┣⬆Firmware {} available", firmware.version).as_str(),
+                    TrayEvent::Configure,
+                );
+            }
+            if let Some((receiver, version)) = &dev.receiver {
+                mb = mb.item(
+                    format!("┣📡{} {}", receiver, version).as_str(),
+                    TrayEvent::None,
+                );
+            }
+            mb = mb.item(format!("┗🛈{}", dev.version).as_str(), TrayEvent::None);
         }
         mb.item("Configure", TrayEvent::Configure)
             .item("✖ Close", TrayEvent::Close)
     }
 
-    pub async fn clear(&mut self) {
-        self.dev = None;
-        self.changes = 0;
+    fn gen_scroll_menu(id: usize, dev: &Device) -> MenuBuilder<TrayEvent> {
+        let mut mb = MenuBuilder::new();
+        if let Some(scroll_natural) = dev.scroll_natural {
+            mb = mb.checkable(
+                "Natural scrolling",
+                scroll_natural,
+                TrayEvent::Command(id, Command::ToggleScrollNatural),
+            );
+        }
+        if let Some(scroll) = &dev.scroll {
+            mb = mb
+                .submenu(
+                    "Speed",
+                    (1..=SCROLL_SPEED_MAX).fold(MenuBuilder::new(), |mb, v| {
+                        mb.checkable(
+                            v.to_string().as_str(),
+                            v == scroll.speed,
+                            TrayEvent::Command(id, Command::ScrollSpeed(v)),
+                        )
+                    }),
+                )
+                .submenu(
+                    "Inertia",
+                    (0..=SCROLL_INERTIA_MAX).fold(MenuBuilder::new(), |mb, v| {
+                        mb.checkable(
+                            if v == 0 {
+                                "Off".to_string()
+                            } else {
+                                v.to_string()
+                            }
+                            .as_str(),
+                            v == scroll.inertia,
+                            TrayEvent::Command(id, Command::ScrollInertia(v)),
+                        )
+                    }),
+                )
+                .submenu(
+                    "Lines per notch",
+                    (1..=SCROLL_LINES_MAX).fold(MenuBuilder::new(), |mb, v| {
+                        mb.checkable(
+                            v.to_string().as_str(),
+                            v == scroll.spl,
+                            TrayEvent::Command(id, Command::ScrollLines(v)),
+                        )
+                    }),
+                );
+        }
+        mb
+    }
+
+    // A mouse plugged in by cable next to its receiver reports through both, only
+    // its cabled entry is shown then. The product id tells the model, not the unit.
+    fn duplicate(&self, td: &TrayDevice) -> bool {
+        td.dev.receiver.is_some()
+            && td.dev.product_id != 0
+            && self
+                .devs
+                .values()
+                .any(|o| o.dev.receiver.is_none() && o.dev.product_id == td.dev.product_id)
+    }
+
+    // Tooltip, icon and menu for all the devices, the icon follows the emptiest battery
+    // and carries a badge while a firmware update is available for any of them
+    async fn refresh(&self) {
         let mut til = self.tray_icon.lock().await;
-        til.set_tooltip("Keychron").ok();
-        til.set_status(TrayIconStatus::Passive).ok();
-        til.set_icon(&self.icon).ok();
+        let battery = self
+            .devs
+            .values()
+            .map(|td| td.dev.battery)
+            .filter(|&b| b != 255)
+            .min();
+        let tooltip = self
+            .devs
+            .values()
+            .filter(|td| !self.duplicate(td))
+            .map(|td| {
+                let dev = &td.dev;
+                format!(
+                    "{} {}{}%{}",
+                    dev.name,
+                    if dev.charging {
+                        "⚡"
+                    } else {
+                        if dev.battery <= 25 { "🪫" } else { "🔋" }
+                    },
+                    dev.battery,
+                    td.firmware
+                        .as_ref()
+                        .map_or("".to_string(), |f| format!(" ⬆Firmware {}", f.version))
+                )
+            })
+            .collect::<Vec<String>>()
+            .join("\n");
+        til.set_tooltip(if tooltip.is_empty() {
+            "Keychron"
+        } else {
+            tooltip.as_str()
+        })
+        .ok();
+        let (icon, bat_icons) = if self.devs.values().any(|td| td.firmware.is_some()) {
+            (&self.update_icon, &self.update_bat_icons)
+        } else {
+            (&self.icon, &self.bat_icons)
+        };
+        match battery {
+            None if self.devs.is_empty() => {
+                til.set_status(TrayIconStatus::Passive).ok();
+                til.set_icon(icon).ok();
+            }
+            None => {
+                til.set_status(TrayIconStatus::Active).ok();
+                til.set_icon(icon).ok();
+            }
+            Some(b) if b <= 25 => {
+                til.set_status(TrayIconStatus::NeedsAttention).ok();
+                til.set_icon(&bat_icons[0]).ok();
+            }
+            Some(b) => {
+                til.set_status(TrayIconStatus::Active).ok();
+                til.set_icon(
+                    &bat_icons[if b <= 50 {
+                        1
+                    } else if b <= 75 {
+                        2
+                    } else {
+                        3
+                    }],
+                )
+                .ok();
+            }
+        }
         til.set_menu(&self.gen_menu()).ok();
     }
 
-    pub async fn update_device(&mut self, dev: Device) {
-        if let Some(old_dev) = &self.dev {
-            if self.changes > 0 {
+    pub async fn remove_device(&mut self, id: usize) {
+        self.pending_firmware.remove(&id);
+        if self.devs.remove(&id).is_some() {
+            self.refresh().await;
+        }
+    }
+
+    pub async fn update_device(&mut self, id: usize, mut dev: Device) {
+        if dev.battery != 255 {
+            dev.battery = dev.battery.clamp(0, 100);
+        }
+        if let Some(td) = self.devs.get_mut(&id) {
+            let old_dev = &td.dev;
+            if td.changes > 0 {
+                if old_dev.profile != dev.profile && dev.profile_num > 0 {
+                    Notification::new()
+                        .appname(dev.name.as_str())
+                        .summary(format!("Profile {}", dev.profile + 1).as_str())
+                        .icon("input-mouse")
+                        .show()
+                        .ok();
+                }
                 if old_dev.dpi != dev.dpi {
                     Notification::new()
                         .appname(dev.name.as_str())
@@ -233,59 +584,145 @@ impl Tray {
                         .show()
                         .ok();
                 }
-                if old_dev.polling_rate_level != dev.polling_rate_level {
+                if old_dev.polling_rate != dev.polling_rate
+                    && let Some(polling_rate) = dev.polling_rate
+                {
                     Notification::new()
                         .appname(dev.name.as_str())
-                        .summary(
-                            format!(
-                                "Polling rate {} {}",
-                                dev.polling_rate_level,
-                                TryInto::<PollLevel>::try_into(dev.polling_rate_level)
-                                    .unwrap_or_default()
-                            )
-                            .as_str(),
-                        )
+                        .summary(format!("Polling rate {}", polling_rate).as_str())
                         .icon("input-mouse")
                         .show()
                         .ok();
                 }
             }
-            self.changes += 1;
-        }
-        self.dev = Some(dev);
-        if let Some(dev) = &mut self.dev {
-            if dev.battery != 255 {
-                dev.battery = dev.battery.clamp(0, 100);
+            td.changes += 1;
+            td.dev = dev;
+        } else {
+            self.devs.insert(
+                id,
+                TrayDevice {
+                    dev,
+                    changes: 0,
+                    firmware: None,
+                    #[cfg(any(test, feature = "simulate"))]
+                    pairing: None,
+                },
+            );
+            if let Some(release) = self.pending_firmware.remove(&id) {
+                self.firmware_available(id, release).await;
+                return;
             }
-            let mut til = self.tray_icon.lock().await;
-            let mut tis = TrayIconStatus::Active;
-            til.set_tooltip(
-                format!(
-                    "{}{}%",
-                    if dev.charging {
-                        "⚡"
-                    } else {
-                        if dev.battery <= 25 { "🪫" } else { "🔋" }
-                    },
-                    dev.battery
-                )
-                .as_str(),
-            )
+        }
+        self.refresh().await;
+    }
+
+    pub fn device_name(&self, id: usize) -> &str {
+        self.devs
+            .get(&id)
+            .map_or("Keychron", |td| td.dev.name.as_str())
+    }
+
+    // Tells once per device and error that the device failed, it is tried again
+    pub fn notify_device_error(&mut self, name: &str, e: &str) {
+        if self
+            .errors_notified
+            .insert((name.to_string(), e.to_string()))
+        {
+            Notification::new()
+                .appname(name)
+                .summary("Device failed, trying again")
+                .body(e)
+                .icon("input-mouse")
+                .show()
+                .ok();
+        }
+    }
+
+    pub fn notify_command_error(&self, id: usize, e: &str) {
+        Notification::new()
+            .appname(self.device_name(id))
+            .summary("Failed to apply setting")
+            .body(e)
+            .icon("input-mouse")
+            .show()
             .ok();
-            if dev.battery <= 25 {
-                tis = TrayIconStatus::NeedsAttention;
-                til.set_icon(&self.bat_icons[0]).ok();
-            } else if dev.battery <= 50 {
-                til.set_icon(&self.bat_icons[1]).ok();
-            } else if dev.battery <= 75 {
-                til.set_icon(&self.bat_icons[2]).ok();
-            } else {
-                til.set_icon(&self.bat_icons[3]).ok();
-            }
-            til.set_status(tis).ok();
+    }
 
-            til.set_menu(&self.gen_menu()).ok();
+    pub async fn firmware_available(&mut self, id: usize, release: FirmwareRelease) {
+        // the check may finish before the first report, named by its device
+        if !self.devs.contains_key(&id) {
+            self.pending_firmware.insert(id, release);
+            return;
         }
+        let name = self.device_name(id).to_string();
+        if self
+            .firmware_notified
+            .insert((name.clone(), release.version.clone()))
+        {
+            Notification::new()
+                .appname(name.as_str())
+                .summary(format!("Firmware {} available", release.version).as_str())
+                .body(release.changelog.as_deref().unwrap_or_default())
+                .icon("input-mouse")
+                .show()
+                .ok();
+        }
+        if let Some(td) = self.devs.get_mut(&id) {
+            td.firmware = Some(release);
+            self.refresh().await;
+        }
+    }
+
+    #[cfg(any(test, feature = "simulate"))]
+    pub async fn pairing_progress(&mut self, id: usize, left: Option<Duration>) {
+        if let Some(td) = self.devs.get_mut(&id) {
+            td.pairing = left;
+            self.refresh().await;
+        }
+    }
+
+    #[cfg(any(test, feature = "simulate"))]
+    pub fn notify_pairing(&self) {
+        Notification::new()
+            .appname("Keychron")
+            .summary("Pairing")
+            .body("Switch the mouse to 2.4G and hold its pairing button")
+            .icon("input-mouse")
+            .show()
+            .ok();
+    }
+
+    #[cfg(any(test, feature = "simulate"))]
+    pub fn notify_paired(&self, res: Result<(), String>) {
+        let mut n = Notification::new();
+        n.appname("Keychron").icon("input-mouse");
+        match res {
+            Ok(()) => n.summary("Mouse paired"),
+            Err(e) => n.summary("Pairing failed").body(e.as_str()),
+        };
+        n.show().ok();
+    }
+
+    // Ask before restoring the defaults of the device, blocks until answered
+    #[cfg(target_os = "linux")]
+    fn confirm_restore_defaults() -> bool {
+        let mut do_it = false;
+        if let Ok(n) = Notification::new()
+            .appname("Keychron")
+            .action("restore", "Restore")
+            .action("cancel", "Cancel")
+            .hint(notify_rust::Hint::Resident(true))
+            .summary("Restore default DPI levels")
+            .body("The DPI levels of the device will be restored to their defaults")
+            .icon("input-mouse")
+            .timeout(Timeout::Never)
+            .show()
+        {
+            n.wait_for_action(|action| {
+                do_it = action == "restore";
+            });
+        };
+        do_it
     }
 
     #[cfg(target_os = "linux")]
@@ -296,9 +733,9 @@ impl Tray {
     }
 
     #[cfg(target_os = "linux")]
-    pub async fn notify_udev_rules(&self) -> Result<bool, Box<dyn error::Error + Send + Sync>> {
+    pub async fn notify_udev_rules() -> Result<bool, Box<dyn error::Error + Send + Sync>> {
         let mut do_it = false;
-        if let Ok(n) = Notification::new()
+        Notification::new()
             .appname("Keychron")
             .action("udev", "Install udev rules")
             .hint(notify_rust::Hint::Resident(true))
@@ -307,15 +744,14 @@ impl Tray {
             .timeout(Timeout::Never)
             .show_async()
             .await
-        {
-            n.wait_for_action(|action| match action {
+            .map_err(KeychronError::from)?
+            .wait_for_action(|action| match action {
                 "udev" => {
                     do_it = true;
                 }
                 "__closed" => (),
                 _ => (),
             });
-        };
         if do_it {
             udev::udev_rule_install().await.map(|_| true)
         } else {
@@ -0,0 +1,183 @@
+use std::{env, error::Error, time::Duration};
+
+use serde_json::Value;
+use ureq::Agent;
+
+// Overrides the launcher, e.g. to use a mirror or a local stub
+pub const LAUNCHER_URL_ENV: &str = "KEYCHRON_LAUNCHER_URL";
+const LAUNCHER_URL: &str = "https://launcher.keychron.com";
+const CHANGELOG_FALLBACK_LOCALE: &str = "en-US";
+// Give up on a slow launcher instead of holding a blocking thread
+const LAUNCHER_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct FirmwareRelease {
+    pub version: String,
+    // Download URL of the firmware binary
+    pub path: String,
+    pub changelog: Option<String>,
+}
+
+pub fn launcher_url() -> String {
+    env::var(LAUNCHER_URL_ENV)
+        .ok()
+        .filter(|u| !u.is_empty())
+        .unwrap_or(LAUNCHER_URL.to_string())
+        .trim_end_matches('/')
+        .to_string()
+}
+
+// Latest firmware the launcher offers for the device, if any
+pub fn latest_firmware(
+    vendor_product_id: u32,
+) -> Result<Option<FirmwareRelease>, Box<dyn Error + Send + Sync>> {
+    let url = format!(
+        "{}/api/merchandise/product/vpId/{}",
+        launcher_url(),
+        vendor_product_id
+    );
+    let agent: Agent = Agent::config_builder()
+        .timeout_global(Some(LAUNCHER_TIMEOUT))
+        .build()
+        .into();
+    let body = agent.get(&url).call()?.body_mut().read_to_string()?;
+    Ok(parse_release(&body, &locale(|k| env::var(k).ok()))?)
+}
+
+// Release from a launcher product response, changelog in the given locale if available
+pub fn parse_release(body: &str, locale: &str) -> Result<Option<FirmwareRelease>, &'static str> {
+    let v: Value = serde_json::from_str(body).map_err(|_| "Invalid launcher response")?;
+    if v.get("success").and_then(Value::as_bool) == Some(false) {
+        return Err("Launcher request failed");
+    }
+    let Some(lasted) = v.pointer("/data/firmware/lasted").filter(|l| !l.is_null()) else {
+        return Ok(None);
+    };
+    let version = lasted
+        .get("version")
+        .and_then(Value::as_str)
+        .ok_or("Firmware version missing")?;
+    let desc = lasted.get("desc");
+    let changelog = [locale, CHANGELOG_FALLBACK_LOCALE]
+        .iter()
+        .find_map(|l| desc.and_then(|d| d.get(*l)).and_then(Value::as_str))
+        .map(str::to_string);
+    Ok(Some(FirmwareRelease {
+        version: version.to_string(),
+        path: lasted
+            .get("path")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string(),
+        changelog,
+    }))
+}
+
+// Compare dotted versions numerically, e.g. "1.0.10" is newer than "1.0.9"
+pub fn is_newer(version: &str, current: &str) -> bool {
+    let parse = |v: &str| -> Vec<u32> { v.split('.').map(|p| p.parse().unwrap_or(0)).collect() };
+    let (mut version, mut current) = (parse(version), parse(current));
+    // missing parts count as 0, so "1.0" and "1.0.0" are the same version
+    let len = version.len().max(current.len());
+    version.resize(len, 0);
+    current.resize(len, 0);
+    version > current
+}
+
+// Launcher locale from the environment variables, e.g. "zh_CN.UTF-8" becomes "zh-CN"
+fn locale(var: impl Fn(&str) -> Option<String>) -> String {
+    ["LC_ALL", "LC_MESSAGES", "LANG"]
+        .iter()
+        .find_map(|k| var(k).filter(|v| !v.is_empty()))
+        .and_then(|v| v.split('.').next().map(|l| l.replace('_', "-")))
+        .unwrap_or(CHANGELOG_FALLBACK_LOCALE.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RESPONSES: &str = include_str!("../doc/README.md");
+    const ULTRA_LINK_8K: u32 = 875876392;
+    const M6_8K: u32 = 875876425;
+
+    // The launcher response recorded in the README for the device
+    fn response(vendor_product_id: u32) -> &'static str {
+        let after = RESPONSES
+            .split_once(&format!("vpId/{}`", vendor_product_id))
+            .unwrap()
+            .1;
+        let json = after.split_once("```json\n").unwrap().1;
+        json.split_once("```").unwrap().0
+    }
+
+    #[test]
+    fn release_parsed() {
+        let release = parse_release(response(M6_8K), "en-US").unwrap().unwrap();
+        assert_eq!(release.version, "1.0.1");
+        assert!(release.path.starts_with("https://"));
+        assert!(release.path.contains("release_keychron_m6_8k_fw1.0.1"));
+        assert_eq!(
+            release.changelog.as_deref(),
+            Some(
+                "1. The driver adds new features such as profile switching/report rate/DPI gear number setting."
+            )
+        );
+        let zh = parse_release(response(M6_8K), "zh-CN").unwrap().unwrap();
+        assert!(zh.changelog.unwrap().starts_with("1.驱动增加profile"));
+        let fallback = parse_release(response(M6_8K), "de-DE").unwrap().unwrap();
+        assert_eq!(fallback.changelog, release.changelog);
+
+        let link = parse_release(response(ULTRA_LINK_8K), "en-US")
+            .unwrap()
+            .unwrap();
+        assert_eq!(link.version, "0.1.6");
+        assert_eq!(
+            link.path,
+            "https://sysmgr.keychron.cn/api/upload/bin/24/1762582390953.bin"
+        );
+        assert_eq!(link.changelog, None);
+
+        let none = r#"{"data":{"firmware":{"count":0,"lasted":null}},"success":true}"#;
+        assert_eq!(parse_release(none, "en-US"), Ok(None));
+        assert!(parse_release(r#"{"success":false}"#, "en-US").is_err());
+        assert!(parse_release("<html>", "en-US").is_err());
+    }
+
+    #[test]
+    fn versions_compared() {
+        assert!(is_newer("1.0.1", "1.0.0"));
+        assert!(is_newer("1.0.10", "1.0.9"));
+        assert!(is_newer("1.1", "1.0.9"));
+        assert!(is_newer("1.0.1", "1.0"));
+        assert!(!is_newer("1.0.1", "1.0.1"));
+        assert!(!is_newer("1.0.0", "1.0"));
+        assert!(!is_newer("1.0", "1.0.0"));
+        assert!(!is_newer("0.1.6", "1.0.0"));
+    }
+
+    #[test]
+    fn locale_from_environment() {
+        let env = |vars: &'static [(&str, &str)]| {
+            move |k: &str| {
+                vars.iter()
+                    .find(|(n, _)| *n == k)
+                    .map(|(_, v)| v.to_string())
+            }
+        };
+        assert_eq!(locale(env(&[("LANG", "zh_CN.UTF-8")])), "zh-CN");
+        assert_eq!(
+            locale(env(&[
+                ("LC_ALL", ""),
+                ("LC_MESSAGES", "de_DE"),
+                ("LANG", "zh_CN")
+            ])),
+            "de-DE"
+        );
+        assert_eq!(
+            locale(env(&[("LC_ALL", "en_US.UTF-8"), ("LANG", "zh_CN")])),
+            "en-US"
+        );
+        assert_eq!(locale(env(&[])), "en-US");
+    }
+}